@@ -1,13 +1,22 @@
 use lazy_static::lazy_static;
-// use std::{path::PathBuf, hash::Hash};
+use std::path::PathBuf;
 use structopt::StructOpt;
 use strum::{AsStaticRef, IntoEnumIterator};
 use strum_macros::{AsStaticStr, EnumIter, EnumString};
 
-// use crate::error::Result;
+use crate::{disasm::BytePattern, error::Result, query::Query, symbol::SymbolSource};
 
 #[derive(EnumString, EnumIter, AsStaticStr, Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub(crate) enum ExecutionMode {
+    #[strum(serialize = "real")]
+    Real16,
+
+    #[strum(serialize = "16-bit")]
+    Protected16,
+
+    #[strum(serialize = "32-bit")]
+    Legacy32,
+
     #[strum(serialize = "compat")]
     Compat,
 
@@ -15,27 +24,460 @@ pub(crate) enum ExecutionMode {
     Bit64,
 }
 
-#[derive(EnumString, EnumIter, AsStaticStr, Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(EnumString, EnumIter, AsStaticStr, Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub(crate) enum ExecutionPrivilege {
     #[strum(serialize = "user")]
     User,
 
+    #[strum(serialize = "ring2")]
+    Ring2,
+
+    #[strum(serialize = "ring1")]
+    Ring1,
+
     #[strum(serialize = "kernel")]
     Kernel,
 }
 
+#[derive(EnumString, EnumIter, AsStaticStr, Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum BadRecordPolicy {
+    #[strum(serialize = "abort")]
+    Abort,
+
+    #[strum(serialize = "skip")]
+    Skip,
+
+    #[strum(serialize = "report")]
+    Report,
+}
+
+#[derive(EnumString, EnumIter, AsStaticStr, Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum GraphFormat {
+    #[strum(serialize = "dot")]
+    Dot,
+
+    #[strum(serialize = "json")]
+    Json,
+}
+
+#[derive(EnumString, EnumIter, AsStaticStr, Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum ExportFormat {
+    #[strum(serialize = "drcov")]
+    Drcov,
+}
+
+#[derive(EnumString, EnumIter, AsStaticStr, Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum NamingScheme {
+    #[strum(serialize = "iclass")]
+    Iclass,
+
+    #[strum(serialize = "iform")]
+    Iform,
+
+    #[strum(serialize = "remill")]
+    Remill,
+}
+
+#[derive(EnumString, EnumIter, AsStaticStr, Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum Syntax {
+    #[strum(serialize = "intel")]
+    Intel,
+
+    #[strum(serialize = "att")]
+    Att,
+
+    #[strum(serialize = "masm")]
+    Masm,
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Syntax::Intel
+    }
+}
+
 lazy_static! {
     static ref EXECUTION_MODES: Vec<&'static str> =
         { ExecutionMode::iter().map(|e| e.as_static()).collect() };
     static ref EXECUTION_PRIVILEGES: Vec<&'static str> =
         { ExecutionPrivilege::iter().map(|e| e.as_static()).collect() };
+    static ref BAD_RECORD_POLICIES: Vec<&'static str> =
+        { BadRecordPolicy::iter().map(|e| e.as_static()).collect() };
+    static ref GRAPH_FORMATS: Vec<&'static str> =
+        { GraphFormat::iter().map(|e| e.as_static()).collect() };
+    static ref EXPORT_FORMATS: Vec<&'static str> =
+        { ExportFormat::iter().map(|e| e.as_static()).collect() };
+    static ref NAMING_SCHEMES: Vec<&'static str> =
+        { NamingScheme::iter().map(|e| e.as_static()).collect() };
+    static ref SYNTAXES: Vec<&'static str> = { Syntax::iter().map(|e| e.as_static()).collect() };
+}
+
+pub(crate) fn parse_hex(value: &str) -> Result<u64> {
+    let value = value.trim_start_matches("0x").trim_start_matches("0X");
+    u64::from_str_radix(value, 16)
+        .map_err(|_| application_error!(format!("bad hexadecimal value: {}", value)))
+}
+
+// a trace name is a part of the cached list names, and of file paths in a store directory
+fn parse_trace_name(trace_name: &str) -> Result<String> {
+    const RESERVED_CHARACTERS: &[char] = &['/', '\\', ':', '<', '>', '"', '|', '?', '*'];
+
+    if trace_name.is_empty()
+        || trace_name == "."
+        || trace_name == ".."
+        || trace_name
+            .chars()
+            .any(|c| c.is_control() || RESERVED_CHARACTERS.contains(&c))
+    {
+        return Err(application_error!(format!(
+            "bad trace name: {}",
+            trace_name
+        )));
+    }
+
+    Ok(trace_name.to_owned())
+}
+
+fn parse_address_range(range: &str) -> Result<(u64, u64)> {
+    let bounds: Vec<_> = range.splitn(2, '-').collect();
+    if bounds.len() != 2 {
+        return Err(application_error!(format!("bad address range: {}", range)));
+    }
+
+    let (begin, end) = (parse_hex(bounds[0])?, parse_hex(bounds[1])?);
+    if begin >= end {
+        return Err(application_error!(format!("bad address range: {}", range)));
+    }
+
+    Ok((begin, end))
+}
+
+// formatting of the disassembly, by default: Intel syntax, lowercase hexadecimal, no padding,
+// signed displacements and RIP-relative offsets
+#[derive(StructOpt, Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct FormatterProfile {
+    #[structopt(
+        name = "syntax",
+        long = "syntax",
+        help = "assembly syntax",
+        default_value = "intel",
+        raw(possible_values = "&SYNTAXES")
+    )]
+    pub syntax: Syntax,
+
+    #[structopt(
+        name = "uppercase hex",
+        long = "uppercase-hex",
+        help = "format hexadecimal numbers in uppercase"
+    )]
+    pub uppercase_hex: bool,
+
+    #[structopt(
+        name = "padding",
+        long = "padding",
+        help = "pad displacements and immediates to their operand size"
+    )]
+    pub padding: bool,
+
+    #[structopt(
+        name = "unsigned displacement",
+        long = "unsigned-disp",
+        help = "format displacements as unsigned numbers"
+    )]
+    pub unsigned_displacement: bool,
+
+    #[structopt(
+        name = "absolute targets",
+        long = "absolute-targets",
+        help = "format branch targets and RIP-relative operands as absolute addresses, \
+                computed from the program counter of the basic block"
+    )]
+    pub absolute_targets: bool,
 }
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "client option")]
-pub(crate) enum Opt {
+pub(crate) struct Opt {
+    #[structopt(
+        name = "store directory",
+        long = "store",
+        help = "use a file backed store in the directory instead of the Redis server",
+        parse(from_os_str)
+    )]
+    pub store_directory: Option<PathBuf>,
+
+    #[structopt(
+        name = "redis url",
+        long = "redis-url",
+        help = "location of the Redis server",
+        default_value = "redis://localhost"
+    )]
+    pub redis_url: String,
+
+    #[structopt(
+        name = "database",
+        long = "db",
+        help = "Redis database number (overrides the one in the url)"
+    )]
+    pub database: Option<i64>,
+
+    #[structopt(
+        name = "trace name",
+        short = "t",
+        long = "trace-name",
+        help = "namespace of the cached lists of a trace",
+        parse(try_from_str = "parse_trace_name")
+    )]
+    pub trace_name: Option<String>,
+
+    #[structopt(
+        name = "batch size",
+        long = "batch-size",
+        help = "number of basic blocks fetched from the cache in a single round trip",
+        default_value = "1024"
+    )]
+    pub batch_size: usize,
+
+    #[structopt(
+        name = "bad record policy",
+        long = "on-bad-record",
+        help = "abort on, skip or report (then skip) records which cannot be decoded",
+        default_value = "abort",
+        raw(possible_values = "&BAD_RECORD_POLICIES")
+    )]
+    pub bad_record_policy: BadRecordPolicy,
+
+    #[structopt(
+        name = "symbols",
+        long = "symbols",
+        help = "symbolize addresses from an ELF file, a nm map, System.map or kallsyms, \
+                optionally loaded at a hexadecimal base (path[@base]); may be repeated",
+        parse(try_from_str = "SymbolSource::parse")
+    )]
+    pub symbol_sources: Vec<SymbolSource>,
+
+    #[structopt(
+        name = "module map",
+        long = "modules",
+        help = "module map file (lines of: name base size [path]) tagging basic blocks \
+                with their module",
+        parse(from_os_str)
+    )]
+    pub module_map_file: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "client command")]
+pub(crate) enum Command {
     #[structopt(name = "cache")]
-    CacheOpt {},
+    CacheOpt {
+        #[structopt(
+            name = "naming",
+            long = "naming",
+            help = "instructions are deduplicated by name: XED iclass, XED iform \
+                    or remill semantic function",
+            default_value = "iclass",
+            raw(possible_values = "&NAMING_SCHEMES")
+        )]
+        naming: NamingScheme,
+
+        #[structopt(
+            name = "restart",
+            long = "restart",
+            help = "go through all basic blocks again instead of resuming the previous run \
+                    (instructions already cached are still not duplicated)"
+        )]
+        restart: bool,
+
+        #[structopt(
+            name = "jobs",
+            short = "j",
+            long = "jobs",
+            help = "number of threads decoding basic blocks",
+            default_value = "1"
+        )]
+        jobs: usize,
+    },
+
+    #[structopt(name = "cfg")]
+    CfgOpt {
+        #[structopt(
+            name = "format",
+            short = "f",
+            long = "format",
+            help = "output format of the control flow graph",
+            default_value = "dot",
+            raw(possible_values = "&GRAPH_FORMATS")
+        )]
+        format: GraphFormat,
+
+        #[structopt(
+            name = "output file",
+            short = "o",
+            long = "output",
+            help = "write to the file instead of the standard output",
+            parse(from_os_str)
+        )]
+        output_file: Option<PathBuf>,
+    },
+
+    #[structopt(name = "functions")]
+    FunctionsOpt {
+        #[structopt(
+            name = "top",
+            short = "n",
+            long = "top",
+            help = "show only the hottest functions"
+        )]
+        top: Option<usize>,
+    },
+
+    #[structopt(name = "stats")]
+    StatsOpt {
+        #[structopt(
+            name = "top",
+            short = "n",
+            long = "top",
+            help = "number of hottest basic blocks shown",
+            default_value = "20"
+        )]
+        top: usize,
+
+        #[structopt(name = "json", long = "json", help = "output in JSON")]
+        json: bool,
+    },
+
+    #[structopt(name = "export")]
+    ExportOpt {
+        #[structopt(
+            name = "format",
+            short = "f",
+            long = "format",
+            help = "coverage format",
+            default_value = "drcov",
+            raw(possible_values = "&EXPORT_FORMATS")
+        )]
+        format: ExportFormat,
+
+        #[structopt(
+            name = "output file",
+            short = "o",
+            long = "output",
+            help = "write to the file instead of the standard output",
+            parse(from_os_str)
+        )]
+        output_file: Option<PathBuf>,
+
+        // also accepted after the subcommand, where it was introduced, overriding the global
+        // module map
+        #[structopt(
+            name = "module map",
+            long = "modules",
+            help = "module map file (lines of: name base size [path]) overriding the global \
+                    one, the trace is covered by synthetic modules if there is no map",
+            parse(from_os_str)
+        )]
+        module_map_file: Option<PathBuf>,
+    },
+
+    #[structopt(name = "list-traces")]
+    ListTracesOpt {},
+
+    #[structopt(name = "ingest")]
+    IngestOpt {
+        #[structopt(
+            name = "trace file",
+            long = "trace",
+            help = "LZ4 compressed trace file",
+            parse(from_os_str)
+        )]
+        trace_file: PathBuf,
+
+        #[structopt(
+            name = "metadata file",
+            long = "metadata",
+            help = "metadata file of the trace",
+            parse(from_os_str)
+        )]
+        metadata_file: PathBuf,
+    },
+
+    #[structopt(name = "sequence")]
+    SequenceOpt {
+        #[structopt(
+            name = "instruction patterns",
+            help = "ordered instruction queries, each one matched by a distinct basic block \
+                    (e.g. syscall swapgs)",
+            raw(required = "true"),
+            parse(try_from_str = "Query::parse")
+        )]
+        patterns: Vec<Query>,
+
+        #[structopt(
+            name = "window",
+            short = "w",
+            long = "within",
+            help = "maximal number of consecutive basic blocks spanned by a match",
+            default_value = "5"
+        )]
+        window_size: usize,
+
+        #[structopt(
+            name = "starting index",
+            short = "g",
+            long = "goto",
+            help = "start searching from the basic block of index",
+            default_value = "0"
+        )]
+        starting_index: usize,
+
+        #[structopt(flatten)]
+        formatter_profile: FormatterProfile,
+    },
+
+    #[structopt(name = "transitions")]
+    TransitionsOpt {
+        #[structopt(
+            name = "summary",
+            short = "s",
+            long = "summary",
+            help = "show only the transition counts per entry point"
+        )]
+        summary_only: bool,
+    },
+
+    #[structopt(name = "mode-switches")]
+    ModeSwitchesOpt {},
+
+    #[structopt(name = "isa-report")]
+    IsaReportOpt {},
+
+    #[structopt(name = "coverage")]
+    CoverageOpt {
+        #[structopt(
+            name = "supported instructions",
+            long = "supported",
+            help = "file of the instruction names supported by the lifter, one per line",
+            parse(from_os_str)
+        )]
+        supported_file: PathBuf,
+
+        #[structopt(
+            name = "naming",
+            long = "naming",
+            help = "instructions are named by: XED iclass, XED iform \
+                    or remill semantic function",
+            default_value = "iclass",
+            raw(possible_values = "&NAMING_SCHEMES")
+        )]
+        naming: NamingScheme,
+    },
+
+    #[structopt(name = "verify")]
+    VerifyOpt {},
 
     #[structopt(name = "show")]
     ShowOpt {
@@ -57,9 +499,46 @@ pub(crate) enum Opt {
             name = "instruction pattern",
             short = "p",
             long = "pattern",
-            help = "search for instructions containing the pattern"
+            help = "search for basic blocks matching the instruction query \
+                    (e.g. \"[mov, dst=cr3] | /^wrmsr/\")",
+            parse(try_from_str = "Query::parse")
+        )]
+        instruction_pattern: Option<Query>,
+
+        #[structopt(
+            name = "byte pattern",
+            short = "b",
+            long = "bytes",
+            help = "search for basic blocks containing the hexadecimal byte pattern \
+                    (?? matches any byte, e.g. \"0f 01 ?? ??\")",
+            parse(try_from_str = "BytePattern::parse")
+        )]
+        byte_pattern: Option<BytePattern>,
+
+        #[structopt(
+            name = "address",
+            short = "a",
+            long = "address",
+            help = "search for basic blocks with an instruction covering the hexadecimal address",
+            parse(try_from_str = "parse_hex")
+        )]
+        address: Option<u64>,
+
+        #[structopt(
+            name = "address range",
+            long = "address-range",
+            help = "search for basic blocks with an instruction overlapping the hexadecimal \
+                    address range begin-end (end excluded)",
+            parse(try_from_str = "parse_address_range")
         )]
-        instruction_pattern: Option<String>,
+        address_range: Option<(u64, u64)>,
+
+        #[structopt(
+            name = "module",
+            long = "module",
+            help = "show only basic blocks in the module of the module map"
+        )]
+        module: Option<String>,
 
         #[structopt(
             name = "execution mode",
@@ -87,6 +566,9 @@ pub(crate) enum Opt {
             default_value = "0"
         )]
         starting_index: usize,
+
+        #[structopt(flatten)]
+        formatter_profile: FormatterProfile,
         // #[structopt(
         //     name = "cache instruction",
         //     long = "cache",
@@ -166,26 +648,41 @@ pub(crate) struct ShowingClientOpt {
     pub execution_mode: Option<ExecutionMode>,
     pub execution_privilege: Option<ExecutionPrivilege>,
     pub starting_index: usize,
-    pub instruction_pattern: Option<String>,
+    pub instruction_pattern: Option<Query>,
+    pub byte_pattern: Option<BytePattern>,
+    pub address: Option<u64>,
+    pub address_range: Option<(u64, u64)>,
+    pub module: Option<String>,
+    pub formatter_profile: FormatterProfile,
     // pub cache_instruction: bool,
     // pub verbosity: u8,
 }
 
 impl ShowingClientOpt {
-    pub fn from(opt: Opt) -> Option<Self> {
+    pub fn from(opt: Command) -> Option<Self> {
         // let opt = Opt::from_args();
         match opt {
-            Opt::ShowOpt {
+            Command::ShowOpt {
                 execution_mode,
                 execution_privilege,
                 starting_index,
                 instruction_pattern,
+                byte_pattern,
+                address,
+                address_range,
+                module,
+                formatter_profile,
             } => Some(ShowingClientOpt {
                 // database: opt.database,
                 execution_mode,
                 execution_privilege,
                 starting_index,
                 instruction_pattern,
+                byte_pattern,
+                address,
+                address_range,
+                module,
+                formatter_profile,
                 // cache_instruction: opt.cache_instruction,
                 // verbosity: opt.verbosity,
             }),
@@ -1,139 +1,198 @@
 use std::{
+    collections::VecDeque,
+    convert::TryFrom,
     fmt::{self, Display},
     io::{Cursor, Read},
     marker::PhantomData,
+    path::Path,
 };
 
-use redis::{cmd, Client, Commands, Connection};
-use scroll::IOread;
+use scroll::{IOread, IOwrite};
 use strum::AsStaticRef;
 
-use crate::args::{ExecutionMode, ExecutionPrivilege};
-
-// struct RawBasicBlock {
-//     pub program_counter: u64,
-//     pub execution_mode: u8,
-//     pub execution_privilege: u8,
-//     pub loop_count: u64,
-//     pub data: Vec<u8>,
-// }
-
-// impl From<&[u8]> for RawBasicBlock {
-//     fn from(raw: &[u8]) -> Self {
-//         let mut raw = Cursor::new(raw);
-//         let program_counter = raw.ioread::<u64>().unwrap();
-//         let execution_mode = raw.ioread::<u8>().unwrap();
-//         let execution_privilege = raw.ioread::<u8>().unwrap();
-//         let loop_count = raw.ioread::<u64>().unwrap();
-//         let mut data = Vec::new();
-//         raw.read_to_end(&mut data).unwrap();
-
-//         RawBasicBlock {
-//             program_counter,
-//             execution_mode,
-//             execution_privilege,
-//             loop_count,
-//             data,
-//         }
-//     }
-// }
+use crate::{
+    args::{BadRecordPolicy, ExecutionMode, ExecutionPrivilege},
+    error::{Error, RecordError},
+    storage::{FileStorage, RedisStorage, Storage},
+};
 
-pub(crate) struct AddressIndependentBasicBlock {
-    pub execution_mode: ExecutionMode,
-    pub data: Vec<u8>,
+// execution mode byte of the trace records, as written by the tracer and copied unchanged by
+// the cache builder (src/Cache/Library.fs): the tracer emits 0 for the compatibility submode
+// and 1 for 64-bit mode. The legacy modes have no value in the tracer's encoding yet, 2, 3
+// and 4 are reserved for them here and must be matched by any tracer emitting them; any
+// other byte is rejected as an unknown mode rather than decoded as some other mode.
+const EXECUTION_MODE_COMPAT: u8 = 0;
+const EXECUTION_MODE_BIT64: u8 = 1;
+const EXECUTION_MODE_LEGACY32: u8 = 2;
+const EXECUTION_MODE_PROTECTED16: u8 = 3;
+const EXECUTION_MODE_REAL16: u8 = 4;
+
+impl TryFrom<u8> for ExecutionMode {
+    type Error = RecordError;
+
+    fn try_from(raw: u8) -> std::result::Result<Self, Self::Error> {
+        match raw {
+            EXECUTION_MODE_COMPAT => Ok(ExecutionMode::Compat),
+
+            EXECUTION_MODE_BIT64 => Ok(ExecutionMode::Bit64),
+
+            EXECUTION_MODE_LEGACY32 => Ok(ExecutionMode::Legacy32),
+
+            EXECUTION_MODE_PROTECTED16 => Ok(ExecutionMode::Protected16),
+
+            EXECUTION_MODE_REAL16 => Ok(ExecutionMode::Real16),
+
+            _ => Err(RecordError::UnknownExecutionMode(raw)),
+        }
+    }
 }
 
-impl From<Vec<u8>> for AddressIndependentBasicBlock {
-    fn from(raw: Vec<u8>) -> Self {
-        let mut raw = Cursor::new(raw);
-        let execution_mode = raw.ioread::<u8>().unwrap();
-        let mut data = Vec::new();
-        raw.read_to_end(&mut data).unwrap();
+impl From<ExecutionMode> for u8 {
+    fn from(mode: ExecutionMode) -> Self {
+        match mode {
+            ExecutionMode::Compat => EXECUTION_MODE_COMPAT,
 
-        let execution_mode = match execution_mode {
-            0 => ExecutionMode::Compat,
+            ExecutionMode::Bit64 => EXECUTION_MODE_BIT64,
 
-            1 => ExecutionMode::Bit64,
+            ExecutionMode::Legacy32 => EXECUTION_MODE_LEGACY32,
 
-            _ => unreachable!(),
-        };
+            ExecutionMode::Protected16 => EXECUTION_MODE_PROTECTED16,
 
-        AddressIndependentBasicBlock {
-            execution_mode,
-            data: data,
+            ExecutionMode::Real16 => EXECUTION_MODE_REAL16,
         }
     }
 }
 
-pub(crate) struct BasicBlock {
+// privilege byte of the trace records: the current privilege level, 0 to 3
+impl TryFrom<u8> for ExecutionPrivilege {
+    type Error = RecordError;
+
+    fn try_from(raw: u8) -> std::result::Result<Self, Self::Error> {
+        match raw {
+            0 => Ok(ExecutionPrivilege::Kernel),
+
+            1 => Ok(ExecutionPrivilege::Ring1),
+
+            2 => Ok(ExecutionPrivilege::Ring2),
+
+            3 => Ok(ExecutionPrivilege::User),
+
+            _ => Err(RecordError::UnknownExecutionPrivilege(raw)),
+        }
+    }
+}
+
+// on-wire layout of a basic block in the basic block list, shared by the ingester
+// (which writes it) and the readers below:
+//   program counter (u64) | execution mode (u8) | privilege (u8) | loop count (u64) | data
+pub(crate) struct RawBasicBlock {
     pub program_counter: u64,
-    pub execution_mode: ExecutionMode,
-    pub execution_privilege: ExecutionPrivilege,
+    pub execution_mode: u8,
+    pub execution_privilege: u8,
     pub loop_count: u64,
     pub data: Vec<u8>,
 }
 
-// impl From<RawBasicBlock> for BasicBlock {
-//     fn from(raw: RawBasicBlock) -> Self {
-//         let execution_mode = match raw.execution_mode {
-//             0 => ExecutionMode::Compat,
+impl TryFrom<Vec<u8>> for RawBasicBlock {
+    type Error = RecordError;
 
-//             1 => ExecutionMode::Bit64,
+    fn try_from(raw: Vec<u8>) -> std::result::Result<Self, Self::Error> {
+        let mut raw = Cursor::new(raw);
+        let program_counter = raw.ioread::<u64>()?;
+        let execution_mode = raw.ioread::<u8>()?;
+        let execution_privilege = raw.ioread::<u8>()?;
+        let loop_count = raw.ioread::<u64>()?;
+        let mut data = Vec::new();
+        raw.read_to_end(&mut data)?;
 
-//             _ => unreachable!(),
-//         };
+        Ok(RawBasicBlock {
+            program_counter,
+            execution_mode,
+            execution_privilege,
+            loop_count,
+            data,
+        })
+    }
+}
+
+impl RawBasicBlock {
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut serialized = Vec::with_capacity(18 + self.data.len());
+        serialized.iowrite(self.program_counter)?;
+        serialized.iowrite(self.execution_mode)?;
+        serialized.iowrite(self.execution_privilege)?;
+        serialized.iowrite(self.loop_count)?;
+        serialized.extend_from_slice(&self.data);
+
+        Ok(serialized)
+    }
 
-//         let execution_privilege = match raw.execution_privilege {
-//             0 => ExecutionPrivilege::Kernel,
+    // layout in the address independent basic block list: execution mode (u8) | data
+    pub fn serialize_address_independent(&self) -> Vec<u8> {
+        let mut serialized = Vec::with_capacity(1 + self.data.len());
+        serialized.push(self.execution_mode);
+        serialized.extend_from_slice(&self.data);
 
-//             3 => ExecutionPrivilege::User,
+        serialized
+    }
+}
 
-//             _ => unreachable!(),
-//         };
+pub(crate) struct AddressIndependentBasicBlock {
+    pub execution_mode: ExecutionMode,
+    pub data: Vec<u8>,
+}
 
-//         BasicBlock {
-//             program_counter: raw.program_counter,
-//             execution_mode,
-//             execution_privilege,
-//             loop_count: raw.loop_count,
-//             data: raw.data,
-//         }
-//     }
-// }
+impl TryFrom<Vec<u8>> for AddressIndependentBasicBlock {
+    type Error = RecordError;
 
-impl From<Vec<u8>> for BasicBlock {
-    fn from(raw: Vec<u8>) -> Self {
+    fn try_from(raw: Vec<u8>) -> std::result::Result<Self, Self::Error> {
         let mut raw = Cursor::new(raw);
-        let program_counter = raw.ioread::<u64>().unwrap();
-        let execution_mode = raw.ioread::<u8>().unwrap();
-        let execution_privilege = raw.ioread::<u8>().unwrap();
-        let loop_count = raw.ioread::<u64>().unwrap();
+        let execution_mode = raw.ioread::<u8>()?;
         let mut data = Vec::new();
-        raw.read_to_end(&mut data).unwrap();
-
-        let execution_mode = match execution_mode {
-            0 => ExecutionMode::Compat,
+        raw.read_to_end(&mut data)?;
 
-            1 => ExecutionMode::Bit64,
+        Ok(AddressIndependentBasicBlock {
+            execution_mode: ExecutionMode::try_from(execution_mode)?,
+            data: data,
+        })
+    }
+}
 
-            _ => unreachable!(),
-        };
+pub(crate) struct BasicBlock {
+    pub program_counter: u64,
+    pub execution_mode: ExecutionMode,
+    pub execution_privilege: ExecutionPrivilege,
+    pub loop_count: u64,
+    pub data: Vec<u8>,
+}
 
-        let execution_privilege = match execution_privilege {
-            0 => ExecutionPrivilege::Kernel,
+impl BasicBlock {
+    // times the basic block was executed: a traced basic block is executed at least once,
+    // whatever its loop count
+    pub fn executions(&self) -> u64 {
+        self.loop_count.max(1)
+    }
+}
 
-            3 => ExecutionPrivilege::User,
+impl TryFrom<RawBasicBlock> for BasicBlock {
+    type Error = RecordError;
+
+    fn try_from(raw: RawBasicBlock) -> std::result::Result<Self, Self::Error> {
+        Ok(BasicBlock {
+            program_counter: raw.program_counter,
+            execution_mode: ExecutionMode::try_from(raw.execution_mode)?,
+            execution_privilege: ExecutionPrivilege::try_from(raw.execution_privilege)?,
+            loop_count: raw.loop_count,
+            data: raw.data,
+        })
+    }
+}
 
-            _ => unreachable!(),
-        };
+impl TryFrom<Vec<u8>> for BasicBlock {
+    type Error = RecordError;
 
-        BasicBlock {
-            program_counter: program_counter,
-            execution_mode,
-            execution_privilege,
-            loop_count: loop_count,
-            data: data,
-        }
+    fn try_from(raw: Vec<u8>) -> std::result::Result<Self, Self::Error> {
+        BasicBlock::try_from(RawBasicBlock::try_from(raw)?)
     }
 }
 
@@ -152,13 +211,20 @@ impl Display for BasicBlock {
 use crate::error::Result;
 
 pub(crate) struct Cache {
-    connection: Connection,
+    storage: Box<dyn Storage>,
+    batch_size: usize,
+    bad_record_policy: BadRecordPolicy,
 }
 
+const DEFAULT_BATCH_SIZE: usize = 1024;
+
 pub(crate) struct CachedBasicBlockIter<'a, 'b, T> {
-    connection: &'a Connection,
+    storage: &'a dyn Storage,
     database: &'b str,
     next_index: usize,
+    batch_size: usize,
+    bad_record_policy: BadRecordPolicy,
+    prefetched: VecDeque<Vec<u8>>,
     pub count: usize,
     phantom: PhantomData<T>,
 }
@@ -167,57 +233,119 @@ pub(crate) struct CachedBasicBlockIter<'a, 'b, T> {
 // and: http://bluejekyll.github.io/blog/rust/2017/08/06/type-parameters.html
 impl<'a, 'b, T> Iterator for CachedBasicBlockIter<'a, 'b, T>
 where
-    T: From<Vec<u8>>,
+    T: TryFrom<Vec<u8>, Error = RecordError>,
 {
-    type Item = T;
+    // records come with their index in the list
+    type Item = Result<(usize, T)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.next_index >= self.count {
-            None
-        } else {
-            let data: std::result::Result<Vec<u8>, _> = self
-                .connection
-                .lindex(self.database, self.next_index as isize);
+        while self.next_index < self.count {
+            if self.prefetched.is_empty() {
+                // fetch the next batch in a single round trip
+                match self
+                    .storage
+                    .list_range(self.database, self.next_index, self.batch_size)
+                {
+                    Ok(batch) => self.prefetched.extend(batch),
+
+                    Err(err) => {
+                        // nothing more can be read reliably
+                        self.next_index = self.count;
+                        return Some(Err(err));
+                    }
+                }
+            }
 
-            let data = data.unwrap();
+            let data = self.prefetched.pop_front()?;
 
+            let index = self.next_index;
             self.next_index += 1;
 
-            Some(From::from(data))
+            match T::try_from(data) {
+                Ok(record) => return Some(Ok((index, record))),
+
+                Err(error) => {
+                    let err = Error::Record { index, error };
+                    match self.bad_record_policy {
+                        BadRecordPolicy::Abort => return Some(Err(err)),
+
+                        BadRecordPolicy::Report => eprintln!("{}", err),
+
+                        BadRecordPolicy::Skip => {}
+                    }
+                }
+            }
         }
+
+        None
     }
 }
 
 impl Cache {
-    pub fn from_url(redis_server_url: &str) -> Result<Self> {
-        let client = Client::open(redis_server_url)?;
-        let connection = client.get_connection()?;
+    pub fn from_url(redis_server_url: &str, database: Option<i64>) -> Result<Self> {
+        let storage = RedisStorage::from_url(redis_server_url, database)?;
+
+        Ok(Cache {
+            storage: Box::new(storage),
+            batch_size: DEFAULT_BATCH_SIZE,
+            bad_record_policy: BadRecordPolicy::Abort,
+        })
+    }
+
+    pub fn from_directory(directory: &Path) -> Result<Self> {
+        let storage = FileStorage::from_directory(directory)?;
 
-        Ok(Cache { connection })
+        Ok(Cache {
+            storage: Box::new(storage),
+            batch_size: DEFAULT_BATCH_SIZE,
+            bad_record_policy: BadRecordPolicy::Abort,
+        })
+    }
+
+    // number of basic blocks prefetched by the iterators in a single round trip
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1);
+    }
+
+    // what the iterators do with records which cannot be decoded
+    pub fn set_bad_record_policy(&mut self, bad_record_policy: BadRecordPolicy) {
+        self.bad_record_policy = bad_record_policy;
+    }
+
+    pub fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
+
+    pub fn count(&self, database: &str) -> Result<usize> {
+        self.storage.list_length(database)
+    }
+
+    pub fn basic_block_data(&self, database: &str, index: usize) -> Result<Vec<u8>> {
+        self.storage.list_index(database, index)
+    }
+
+    pub fn push_basic_block_data(&self, database: &str, data: &[u8]) -> Result<()> {
+        self.storage.list_push(database, data)
     }
 
     pub fn basic_blocks<'a, 'b, T>(
         &'a self,
         database: &'b str,
+        starting_index: usize,
     ) -> Result<CachedBasicBlockIter<'a, 'b, T>> {
-        let database_exists: bool = self.connection.exists(database)?;
-        if database_exists {
-            let cached_database_type_name: String =
-                cmd("TYPE").arg(database).query(&self.connection)?;
-
-            if cached_database_type_name == "list" {
-                let count: usize = self.connection.llen(database)?;
-
-                Ok(CachedBasicBlockIter::<T> {
-                    connection: &self.connection,
-                    database,
-                    next_index: 0,
-                    count,
-                    phantom: PhantomData,
-                })
-            } else {
-                Err(application_error!("cached basic block data is not a list"))
-            }
+        if self.storage.list_exists(database)? {
+            let count = self.storage.list_length(database)?;
+
+            Ok(CachedBasicBlockIter::<T> {
+                storage: self.storage.as_ref(),
+                database,
+                next_index: starting_index,
+                batch_size: self.batch_size,
+                bad_record_policy: self.bad_record_policy,
+                prefetched: VecDeque::new(),
+                count,
+                phantom: PhantomData,
+            })
         } else {
             Err(application_error!("cached basic block data does not exist"))
         }
@@ -0,0 +1,166 @@
+use std::{collections::HashMap, io::Write};
+
+use serde_derive::Serialize;
+use strum::AsStaticRef;
+
+use crate::{
+    args::ExecutionMode, cache::BasicBlock, error::Result, module::ModuleMap, symbol::SymbolMap,
+};
+
+#[derive(Serialize)]
+struct CfgNode {
+    id: usize,
+    program_counter: u64,
+    execution_mode: &'static str,
+    size: usize,
+    hit_count: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    symbol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    module: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CfgEdge {
+    source: usize,
+    target: usize,
+    hit_count: u64,
+}
+
+#[derive(Serialize)]
+struct SerializedCfg<'a> {
+    nodes: &'a [CfgNode],
+    edges: Vec<CfgEdge>,
+}
+
+// names from the symbol and module maps end up in quoted DOT labels
+fn escape_dot(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// dynamic control flow graph: nodes are unique (program counter, mode) basic blocks,
+// edges are the successor transitions observed in the trace
+pub(crate) struct ControlFlowGraph {
+    node_indices: HashMap<(u64, ExecutionMode), usize>,
+    nodes: Vec<CfgNode>,
+    edges: HashMap<(usize, usize), u64>,
+    last_node: Option<usize>,
+}
+
+impl ControlFlowGraph {
+    pub fn new() -> Self {
+        ControlFlowGraph {
+            node_indices: HashMap::new(),
+            nodes: vec![],
+            edges: HashMap::new(),
+            last_node: None,
+        }
+    }
+
+    // basic blocks must be added in trace order
+    pub fn add_basic_block(&mut self, basic_block: &BasicBlock) {
+        let node_key = (basic_block.program_counter, basic_block.execution_mode);
+
+        let node = if let Some(&node) = self.node_indices.get(&node_key) {
+            node
+        } else {
+            let node = self.nodes.len();
+            self.nodes.push(CfgNode {
+                id: node,
+                program_counter: basic_block.program_counter,
+                execution_mode: basic_block.execution_mode.as_static(),
+                size: basic_block.data.len(),
+                hit_count: 0,
+                symbol: None,
+                module: None,
+            });
+            self.node_indices.insert(node_key, node);
+            node
+        };
+
+        self.nodes[node].hit_count += basic_block.executions();
+
+        if let Some(last_node) = self.last_node {
+            *self.edges.entry((last_node, node)).or_insert(0) += 1;
+        }
+        // a basic block looping on itself is traced once, with its number of iterations
+        if basic_block.loop_count > 1 {
+            *self.edges.entry((node, node)).or_insert(0) += basic_block.loop_count - 1;
+        }
+        self.last_node = Some(node);
+    }
+
+    pub fn symbolize(&mut self, symbols: &SymbolMap, module_map: &ModuleMap) {
+        for node in &mut self.nodes {
+            node.symbol = symbols.symbolize(node.program_counter);
+            node.module = module_map.tag(node.program_counter);
+        }
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    fn sorted_edges(&self) -> Vec<CfgEdge> {
+        let mut edges: Vec<_> = self
+            .edges
+            .iter()
+            .map(|(&(source, target), &hit_count)| CfgEdge {
+                source,
+                target,
+                hit_count,
+            })
+            .collect();
+        edges.sort_by_key(|edge| (edge.source, edge.target));
+        edges
+    }
+
+    pub fn write_dot<W: Write>(&self, output: &mut W) -> Result<()> {
+        writeln!(output, "digraph cfg {{")?;
+        writeln!(output, "    node [shape=box, fontname=\"monospace\"];")?;
+
+        for node in &self.nodes {
+            let symbol = node
+                .symbol
+                .as_ref()
+                .map_or_else(String::new, |symbol| format!(" <{}>", escape_dot(symbol)));
+            let module = node
+                .module
+                .as_ref()
+                .map_or_else(String::new, |module| format!(" [{}]", escape_dot(module)));
+            writeln!(
+                output,
+                "    n{} [label=\"0x{:016x}{}{} ({})\\nhits: {}\"];",
+                node.id, node.program_counter, module, symbol, node.execution_mode, node.hit_count
+            )?;
+        }
+
+        for edge in self.sorted_edges() {
+            writeln!(
+                output,
+                "    n{} -> n{} [label=\"{}\"];",
+                edge.source, edge.target, edge.hit_count
+            )?;
+        }
+
+        writeln!(output, "}}")?;
+
+        Ok(())
+    }
+
+    pub fn write_json<W: Write>(&self, output: &mut W) -> Result<()> {
+        let serialized_cfg = SerializedCfg {
+            nodes: &self.nodes,
+            edges: self.sorted_edges(),
+        };
+
+        serde_json::to_writer_pretty(&mut *output, &serialized_cfg)?;
+        writeln!(output)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+use fasthash::metro;
+use lru::LruCache;
+use strum::AsStaticRef;
+use tabwriter::TabWriter;
+
+use crate::{
+    args::{ExecutionMode, NamingScheme},
+    cache::BasicBlock,
+    error::Result,
+    iname,
+};
+
+// each non-empty line which is not a comment (#) is the name of a supported instruction
+pub(crate) fn read_supported_names(supported_file: &Path) -> Result<HashSet<String>> {
+    let mut names = HashSet::new();
+
+    for line in BufReader::new(File::open(supported_file)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        names.insert(line.to_owned());
+    }
+
+    Ok(names)
+}
+
+struct InstructionOccurrence {
+    // first encoding seen
+    data: Vec<u8>,
+    execution_mode: ExecutionMode,
+    basic_blocks: u64,
+}
+
+// instruction names of the trace, with the number of basic blocks they appear in
+pub(crate) struct CoverageCollector {
+    naming: NamingScheme,
+    // recently seen basic blocks are not decoded by XED again, they are kept as their
+    // distinct instruction names
+    decoded_basic_blocks: LruCache<(ExecutionMode, u64), Vec<String>>,
+    instructions: HashMap<String, InstructionOccurrence>,
+}
+
+impl CoverageCollector {
+    pub fn new(naming: NamingScheme) -> Self {
+        iname::initialize_xed();
+
+        CoverageCollector {
+            naming,
+            decoded_basic_blocks: LruCache::new(16 * 1024),
+            instructions: HashMap::new(),
+        }
+    }
+
+    pub fn add_basic_block(&mut self, basic_block: &BasicBlock) {
+        let execution_mode = basic_block.execution_mode;
+
+        let basic_block_hash = (execution_mode, metro::hash64(&basic_block.data));
+        if self.decoded_basic_blocks.get(&basic_block_hash).is_none() {
+            let mut names = vec![];
+            for (name, data) in
+                iname::instruction_names(&basic_block.data, execution_mode, self.naming)
+            {
+                if names.contains(&name) {
+                    continue;
+                }
+
+                // a block decoded again once evicted keeps the first encoding seen
+                self.instructions
+                    .entry(name.clone())
+                    .or_insert_with(|| InstructionOccurrence {
+                        data,
+                        execution_mode,
+                        basic_blocks: 0,
+                    });
+                names.push(name);
+            }
+            self.decoded_basic_blocks.put(basic_block_hash, names);
+        }
+
+        let names = self.decoded_basic_blocks.get(&basic_block_hash).unwrap();
+        for name in names.iter() {
+            if let Some(occurrence) = self.instructions.get_mut(name) {
+                occurrence.basic_blocks += 1;
+            }
+        }
+    }
+
+    // instructions which are not supported, from the most frequent one; returns their number
+    pub fn write_missing<W: Write>(
+        &self,
+        supported_names: &HashSet<String>,
+        output: &mut W,
+    ) -> Result<usize> {
+        let mut missing: Vec<_> = self
+            .instructions
+            .iter()
+            .filter(|(name, _)| !supported_names.contains(*name))
+            .collect();
+        missing.sort_by(|(name0, occurrence0), (name1, occurrence1)| {
+            occurrence1
+                .basic_blocks
+                .cmp(&occurrence0.basic_blocks)
+                .then(name0.cmp(name1))
+        });
+
+        let mut tw = TabWriter::new(output).padding(4);
+
+        writeln!(tw, "instruction\tmode\tbasic blocks\tencoding")?;
+        for (name, occurrence) in &missing {
+            let encoding: Vec<_> = occurrence
+                .data
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            writeln!(
+                tw,
+                "{}\t{}\t{}\t{}",
+                name,
+                occurrence.execution_mode.as_static(),
+                occurrence.basic_blocks,
+                encoding.join(" ")
+            )?;
+        }
+        tw.flush()?;
+
+        Ok(missing.len())
+    }
+}
@@ -2,14 +2,83 @@ use std::fmt::{self, Display};
 
 use fasthash::metro;
 use lru::LruCache;
-use zydis::{Decoder, Formatter};
+use zydis::{BranchType, DecodedInstruction, Decoder, Formatter, OperandType, Register};
 
-use crate::{args::ExecutionMode, error::Result};
+use crate::{
+    args::{ExecutionMode, FormatterProfile, Syntax},
+    error::Result,
+    query::Query,
+    symbol::SymbolMap,
+};
 
 pub(crate) struct DisasmInst<'a> {
     pub address: u64,
     pub data: &'a [u8],
+    pub instruction: DecodedInstruction,
     pub disasm: String,
+    // shown as a comment after the instruction
+    pub annotation: Option<String>,
+}
+
+impl<'a> DisasmInst<'a> {
+    // target of a near branch or of a RIP-relative operand, meaningful only if the
+    // instruction is at its real address
+    pub fn target_address(&self) -> Option<u64> {
+        let operands = &self.instruction.operands[..self.instruction.operand_count as usize];
+        operands
+            .iter()
+            .find(|op| {
+                (op.ty == OperandType::IMMEDIATE
+                    && self.instruction.meta.branch_type != BranchType::NONE)
+                    || (op.ty == OperandType::MEMORY && op.mem.base == Register::RIP)
+            })
+            .and_then(|op| {
+                self.instruction
+                    .calc_absolute_address(self.address, op)
+                    .ok()
+            })
+    }
+}
+
+// bytes to search for, none stands for any byte
+#[derive(Debug)]
+pub(crate) struct BytePattern(Vec<Option<u8>>);
+
+impl BytePattern {
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let bytes = pattern
+            .split_whitespace()
+            .map(|byte| {
+                if byte == "??" {
+                    Ok(None)
+                } else {
+                    u8::from_str_radix(byte, 16)
+                        .map(Some)
+                        .map_err(|_| application_error!(format!("bad byte in pattern: {}", byte)))
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if bytes.is_empty() {
+            Err(application_error!("empty byte pattern"))
+        } else {
+            Ok(BytePattern(bytes))
+        }
+    }
+
+    fn matches(&self, data: &[u8]) -> bool {
+        self.0
+            .iter()
+            .zip(data)
+            .all(|(pattern_byte, byte)| pattern_byte.map_or(true, |b| b == *byte))
+    }
+
+    // ref: https://stackoverflow.com/questions/35901547/how-can-i-find-a-subsequence-in-a-u8-slice
+    // raw bytes are searched, so the pattern may span several instructions or cover bytes
+    // which are not decoded
+    pub fn found_in(&self, data: &[u8]) -> bool {
+        data.windows(self.0.len()).any(|w| self.matches(w))
+    }
 }
 
 pub(crate) struct DisasmBasicBlock<'a> {
@@ -17,6 +86,14 @@ pub(crate) struct DisasmBasicBlock<'a> {
 }
 
 impl<'a> DisasmBasicBlock<'a> {
+    pub fn instructions(&self) -> &[DisasmInst<'a>] {
+        &self.instructions
+    }
+
+    pub fn last_instruction(&self) -> Option<&DisasmInst<'a>> {
+        self.instructions.last()
+    }
+
     pub fn contain_address_exact(&self, addr: u64) -> bool {
         self.instructions
             .iter()
@@ -30,28 +107,35 @@ impl<'a> DisasmBasicBlock<'a> {
         {
             first_ins.address <= addr && addr <= last_ins.address
         } else {
-            unreachable!()
+            false
         }
     }
 
-    pub fn contain_instruction_pattern(&self, ins_pat: &str) -> bool {
-        self.instructions
-            .iter()
-            .position(|ins| ins.disasm.contains(ins_pat))
-            .is_some()
+    // an instruction covers the address
+    pub fn cover_address(&self, addr: u64) -> bool {
+        self.instructions.iter().any(|ins| {
+            ins.address <= addr && addr < ins.address.saturating_add(ins.data.len() as u64)
+        })
     }
 
-    // ref: https://stackoverflow.com/questions/35901547/how-can-i-find-a-subsequence-in-a-u8-slice
-    pub fn contains_instruction_bytes(&self, ins_bytes: &[u8]) -> bool {
-        self.instructions
-            .iter()
-            .position(|ins| {
-                ins.data
-                    .windows(ins_bytes.len())
-                    .position(|w| w == ins_bytes)
-                    .is_some()
-            })
-            .is_some()
+    // an instruction overlaps the range [begin, end)
+    pub fn overlap_address_range(&self, begin_addr: u64, end_addr: u64) -> bool {
+        self.instructions.iter().any(|ins| {
+            ins.address < end_addr && begin_addr < ins.address.saturating_add(ins.data.len() as u64)
+        })
+    }
+
+    // annotates instructions with the symbols of their targets
+    pub fn symbolize_targets(&mut self, symbols: &SymbolMap) {
+        for ins in &mut self.instructions {
+            ins.annotation = ins
+                .target_address()
+                .and_then(|target| symbols.symbolize(target));
+        }
+    }
+
+    pub fn contain_instruction_pattern(&self, ins_pat: &Query) -> bool {
+        ins_pat.matches(self)
     }
 }
 
@@ -66,10 +150,17 @@ impl<'a> Display for DisasmBasicBlock<'a> {
                     .map(|b| format!("{:02x}", b))
                     .collect::<Vec<_>>()
                     .join(" ")[..];
-                format!(
-                    "0x{:016x}\t{:45}\t{}",
-                    inst.address, inst_data_str, &inst.disasm
-                )
+                match inst.annotation {
+                    Some(ref annotation) => format!(
+                        "0x{:016x}\t{:45}\t{}\t; {}",
+                        inst.address, inst_data_str, &inst.disasm, annotation
+                    ),
+
+                    None => format!(
+                        "0x{:016x}\t{:45}\t{}",
+                        inst.address, inst_data_str, &inst.disasm
+                    ),
+                }
             };
             formatted_insts.push(formatted_inst);
         }
@@ -80,6 +171,7 @@ impl<'a> Display for DisasmBasicBlock<'a> {
 struct DisasmInstructionLayout {
     pub address: u64,
     pub end_offset: usize,
+    pub instruction: DecodedInstruction,
     pub disasm: String,
 }
 
@@ -88,42 +180,98 @@ struct DisasmBasicBlockLayout {
 }
 
 pub(crate) struct Disasm<'a> {
+    decoder_real_16: Decoder,
+    decoder_16: Decoder,
+    decoder_legacy_32: Decoder,
     decoder_32: Decoder,
     decoder_64: Decoder,
     formatter: Formatter<'a>,
+    // formats instructions at their real address, so it bypasses the layout cache
+    absolute_formatter: Option<Formatter<'a>>,
     layout_cache: LruCache<(ExecutionMode, u64), DisasmBasicBlockLayout>,
     decoded_buffer: [u8; 200],
 }
 
 impl<'a, 'b> Disasm<'a> {
-    pub fn from_args() -> Result<Self> {
+    pub fn from_args(formatter_profile: &FormatterProfile) -> Result<Self> {
         use zydis::*;
 
+        let decoder_real_16 = Decoder::new(MachineMode::Real16, AddressWidth::_16)?;
+        let decoder_16 = Decoder::new(MachineMode::Legacy16, AddressWidth::_16)?;
+        let decoder_legacy_32 = Decoder::new(MachineMode::Legacy32, AddressWidth::_32)?;
         let decoder_32 = Decoder::new(MachineMode::LongCompat32, AddressWidth::_32)?;
         let decoder_64 = Decoder::new(MachineMode::Long64, AddressWidth::_64)?;
 
-        let mut formatter = Formatter::new(FormatterStyle::Intel)?;
+        let formatter = Disasm::formatter_from_profile(formatter_profile, false)?;
+        let absolute_formatter = if formatter_profile.absolute_targets {
+            Some(Disasm::formatter_from_profile(formatter_profile, true)?)
+        } else {
+            None
+        };
+
+        Ok(Disasm {
+            decoder_real_16,
+            decoder_16,
+            decoder_legacy_32,
+            decoder_32,
+            decoder_64,
+            formatter,
+            absolute_formatter,
+            layout_cache: LruCache::new(16 * 1024),
+            decoded_buffer: [0u8; 200],
+        })
+    }
+
+    // branch targets and RIP-relative operands are formatted as absolute addresses by the
+    // formatter of absolute targets, otherwise relative to the instruction
+    fn formatter_from_profile(
+        formatter_profile: &FormatterProfile,
+        absolute_targets: bool,
+    ) -> Result<Formatter<'a>> {
+        use zydis::*;
+
+        let style = match formatter_profile.syntax {
+            Syntax::Intel => FormatterStyle::Intel,
+            Syntax::Att => FormatterStyle::ATT,
+            Syntax::Masm => FormatterStyle::IntelMasm,
+        };
+
+        let padding = if formatter_profile.padding {
+            Padding::Auto
+        } else {
+            Padding::Disabled
+        };
+
+        let displacement_signedness = if formatter_profile.unsigned_displacement {
+            Signedness::Unsigned
+        } else {
+            Signedness::Signed
+        };
+
+        let mut formatter = Formatter::new(style)?;
         formatter.set_property(FormatterProperty::AddressPaddingRelative(Padding::Auto))?;
         formatter.set_property(FormatterProperty::AddressPaddingAbsolute(Padding::Auto))?;
         formatter.set_property(FormatterProperty::AddressSignedness(Signedness::Unsigned))?;
 
-        formatter.set_property(FormatterProperty::DisplacementPadding(Padding::Disabled))?;
+        formatter.set_property(FormatterProperty::DisplacementPadding(padding))?;
         formatter.set_property(FormatterProperty::DisplacementSignedness(
-            Signedness::Signed,
+            displacement_signedness,
         ))?;
-        formatter.set_property(FormatterProperty::ImmediatePadding(Padding::Disabled))?;
+        formatter.set_property(FormatterProperty::ImmediatePadding(padding))?;
         formatter.set_property(FormatterProperty::ImmediateSignedness(Signedness::Unsigned))?;
 
-        formatter.set_property(FormatterProperty::HexUppercase(false))?;
-        formatter.set_property(FormatterProperty::ForceRelativeRiprel(true))?;
+        formatter.set_property(FormatterProperty::HexUppercase(
+            formatter_profile.uppercase_hex,
+        ))?;
 
-        Ok(Disasm {
-            decoder_32,
-            decoder_64,
-            formatter,
-            layout_cache: LruCache::new(16 * 1024),
-            decoded_buffer: [0u8; 200],
-        })
+        if absolute_targets {
+            formatter.set_property(FormatterProperty::ForceRelativeBranches(false))?;
+            formatter.set_property(FormatterProperty::ForceRelativeRiprel(false))?;
+        } else {
+            formatter.set_property(FormatterProperty::ForceRelativeRiprel(true))?;
+        }
+
+        Ok(formatter)
     }
 
     pub fn disasm(
@@ -135,6 +283,9 @@ impl<'a, 'b> Disasm<'a> {
         use zydis::*;
 
         let decoder = match execution_mode {
+            ExecutionMode::Real16 => &self.decoder_real_16,
+            ExecutionMode::Protected16 => &self.decoder_16,
+            ExecutionMode::Legacy32 => &self.decoder_legacy_32,
             ExecutionMode::Compat => &self.decoder_32,
             ExecutionMode::Bit64 => &self.decoder_64,
         };
@@ -158,6 +309,7 @@ impl<'a, 'b> Disasm<'a> {
                 disasm_inst_layouts.push(DisasmInstructionLayout {
                     address: *ins_addr,
                     end_offset: next_decoded_byte_count,
+                    instruction: ins.clone(),
                     disasm: String::from(decoded_buffer.as_str()?),
                 });
 
@@ -176,18 +328,40 @@ impl<'a, 'b> Disasm<'a> {
         let mut disasm_insts = vec![];
         let mut begin_offset = 0usize;
 
+        let absolute_formatter = match base_address {
+            Some(_) => self.absolute_formatter.as_ref(),
+            None => None,
+        };
         let base_address = base_address.unwrap_or(0);
 
         for DisasmInstructionLayout {
             address,
             end_offset,
+            instruction,
             disasm,
         } in &disasm_basic_block_layout.instruction_layouts
         {
+            let disasm = match absolute_formatter {
+                Some(absolute_formatter) => {
+                    let mut decoded_buffer = OutputBuffer::new(&mut self.decoded_buffer);
+                    absolute_formatter.format_instruction(
+                        instruction,
+                        &mut decoded_buffer,
+                        Some(*address + base_address),
+                        None,
+                    )?;
+                    String::from(decoded_buffer.as_str()?)
+                }
+
+                None => disasm.to_string(),
+            };
+
             disasm_insts.push(DisasmInst {
                 address: *address + base_address,
                 data: &data[begin_offset..*end_offset],
-                disasm: disasm.to_string(),
+                instruction: instruction.clone(),
+                disasm,
+                annotation: None,
             });
 
             begin_offset = *end_offset;
@@ -0,0 +1,72 @@
+use std::{collections::BTreeSet, io::Write};
+
+use scroll::IOwrite;
+
+use crate::{error::Result, module::ModuleMap};
+
+// ref: https://dynamorio.org/dynamorio_docs/page_drcov.html
+// blocks are (start, size) pairs; those outside of any module, or too far from their module
+// base to fit in a 32-bit offset, are left out. Returns the number of blocks written.
+pub(crate) fn write_drcov<W: Write>(
+    module_map: &ModuleMap,
+    basic_blocks: &BTreeSet<(u64, u16)>,
+    output: &mut W,
+) -> Result<usize> {
+    // module ids are 16-bit in the block table
+    if module_map.modules().len() > usize::from(u16::max_value()) + 1 {
+        return Err(application_error!(format!(
+            "too many modules for drcov: {} (at most {})",
+            module_map.modules().len(),
+            usize::from(u16::max_value()) + 1
+        )));
+    }
+
+    let mut bb_entries = vec![];
+    for &(start, size) in basic_blocks {
+        if let Some((module_id, module)) = module_map.find(start) {
+            let offset = start - module.base;
+            if offset <= u64::from(u32::max_value()) {
+                bb_entries.push((offset as u32, size, module_id as u16));
+            }
+        }
+    }
+
+    writeln!(output, "DRCOV VERSION: 2")?;
+    writeln!(output, "DRCOV FLAVOR: drcov")?;
+    writeln!(
+        output,
+        "Module Table: version 2, count {}",
+        module_map.modules().len()
+    )?;
+    writeln!(
+        output,
+        "Columns: id, base, end, entry, checksum, timestamp, path"
+    )?;
+    for (module_id, module) in module_map.modules().iter().enumerate() {
+        let path = module
+            .path
+            .as_ref()
+            .and_then(|path| path.to_str())
+            .unwrap_or(&module.name);
+        writeln!(
+            output,
+            "{:3}, 0x{:016x}, 0x{:016x}, 0x{:016x}, 0x{:08x}, 0x{:08x}, {}",
+            module_id,
+            module.base,
+            module.end(),
+            0,
+            0,
+            0,
+            path
+        )?;
+    }
+
+    writeln!(output, "BB Table: {} bbs", bb_entries.len())?;
+    for &(offset, size, module_id) in &bb_entries {
+        output.iowrite_with(offset, scroll::LE)?;
+        output.iowrite_with(size, scroll::LE)?;
+        output.iowrite_with(module_id, scroll::LE)?;
+    }
+
+    Ok(bb_entries.len())
+}
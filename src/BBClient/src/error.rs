@@ -1,5 +1,6 @@
 use failure::Fail;
 use redis::RedisError;
+use serde_json::Error as SerializationError;
 use std::io::Error as IOError;
 use zydis::Status as ZydisError;
 
@@ -14,10 +15,35 @@ pub(in crate) enum Error {
     #[fail(display = "Cache error: {}", _0)]
     Cache(#[cause] RedisError),
 
+    #[fail(display = "Serialization error: {}", _0)]
+    Serialization(#[cause] SerializationError),
+
+    #[fail(display = "Record error at index {}: {}", index, error)]
+    Record {
+        index: usize,
+        #[cause]
+        error: RecordError,
+    },
+
     #[fail(display = "Application error: {}", _0)]
     Application(String),
 }
 
+#[derive(Fail, Debug)]
+pub(crate) enum RecordError {
+    #[fail(display = "truncated record")]
+    Truncated,
+
+    #[fail(display = "unknown execution mode {}", _0)]
+    UnknownExecutionMode(u8),
+
+    #[fail(display = "unknown execution privilege {}", _0)]
+    UnknownExecutionPrivilege(u8),
+
+    #[fail(display = "reference to unknown raw basic block {}", _0)]
+    UnknownBackReference(u64),
+}
+
 impl From<ZydisError> for Error {
     fn from(err: ZydisError) -> Self {
         Error::Disasm(err)
@@ -30,12 +56,24 @@ impl From<IOError> for Error {
     }
 }
 
+impl From<IOError> for RecordError {
+    fn from(_: IOError) -> Self {
+        RecordError::Truncated
+    }
+}
+
 impl From<RedisError> for Error {
     fn from(err: RedisError) -> Self {
         Error::Cache(err)
     }
 }
 
+impl From<SerializationError> for Error {
+    fn from(err: SerializationError) -> Self {
+        Error::Serialization(err)
+    }
+}
+
 pub(crate) type Result<T> = std::result::Result<T, Error>;
 
 #[macro_export]
@@ -0,0 +1,172 @@
+use std::collections::{HashMap, HashSet};
+
+use zydis::Mnemonic;
+
+use crate::{
+    args::{ExecutionMode, ExecutionPrivilege},
+    cache::BasicBlock,
+    disasm::DisasmBasicBlock,
+};
+
+pub(crate) enum Terminator {
+    Call { call_site: u64, return_address: u64 },
+    Return,
+    Other,
+}
+
+impl Terminator {
+    pub fn from_basic_block(disasm_basic_block: &DisasmBasicBlock) -> Self {
+        match disasm_basic_block.last_instruction() {
+            Some(ins) if ins.instruction.mnemonic == Mnemonic::CALL => Terminator::Call {
+                call_site: ins.address,
+                return_address: ins.address + ins.data.len() as u64,
+            },
+
+            Some(ins) if ins.instruction.mnemonic == Mnemonic::RET => Terminator::Return,
+
+            _ => Terminator::Other,
+        }
+    }
+}
+
+pub(crate) type FunctionKey = (u64, ExecutionMode);
+
+pub(crate) struct Function {
+    pub entry: u64,
+    pub execution_mode: ExecutionMode,
+    pub call_count: u64,
+    pub call_sites: HashSet<u64>,
+    pub callees: HashSet<FunctionKey>,
+    pub basic_blocks: HashSet<u64>,
+    // basic blocks of the function in the trace, and the times they were executed (i.e.
+    // weighted by their loop count)
+    pub basic_block_occurrences: u64,
+    pub executions: u64,
+}
+
+impl Function {
+    fn new((entry, execution_mode): FunctionKey) -> Self {
+        Function {
+            entry,
+            execution_mode,
+            call_count: 0,
+            call_sites: HashSet::new(),
+            callees: HashSet::new(),
+            basic_blocks: HashSet::new(),
+            basic_block_occurrences: 0,
+            executions: 0,
+        }
+    }
+}
+
+struct Frame {
+    function: FunctionKey,
+    // none for the outermost frame, whose caller has not been observed
+    return_address: Option<u64>,
+}
+
+#[derive(Default)]
+struct ShadowStack {
+    frames: Vec<Frame>,
+    pending_terminator: Option<Terminator>,
+}
+
+// recovers a dynamic call tree by following call/ret pairs; interrupts and exceptions
+// interleave code of different privileges, so each privilege keeps its own stack
+pub(crate) struct CallTree {
+    functions: HashMap<FunctionKey, Function>,
+    stacks: HashMap<ExecutionPrivilege, ShadowStack>,
+}
+
+impl CallTree {
+    pub fn new() -> Self {
+        CallTree {
+            functions: HashMap::new(),
+            stacks: HashMap::new(),
+        }
+    }
+
+    // basic blocks must be added in trace order
+    pub fn add_basic_block(&mut self, basic_block: &BasicBlock, terminator: Terminator) {
+        let block_key = (basic_block.program_counter, basic_block.execution_mode);
+
+        let stack = self
+            .stacks
+            .entry(basic_block.execution_privilege)
+            .or_insert_with(ShadowStack::default);
+
+        match stack.pending_terminator.take() {
+            Some(Terminator::Call {
+                call_site,
+                return_address,
+            }) => {
+                if let Some(caller) = stack.frames.last() {
+                    let caller = self
+                        .functions
+                        .entry(caller.function)
+                        .or_insert_with(|| Function::new(caller.function));
+                    caller.call_sites.insert(call_site);
+                    caller.callees.insert(block_key);
+                }
+
+                let callee = self
+                    .functions
+                    .entry(block_key)
+                    .or_insert_with(|| Function::new(block_key));
+                callee.call_count += 1;
+
+                stack.frames.push(Frame {
+                    function: block_key,
+                    return_address: Some(return_address),
+                });
+            }
+
+            Some(Terminator::Return) => {
+                // unwind to the frame returning here, or drop a single frame if none does
+                let returning_frame = stack
+                    .frames
+                    .iter()
+                    .rposition(|frame| frame.return_address == Some(block_key.0));
+
+                match returning_frame {
+                    Some(frame) => stack.frames.truncate(frame),
+
+                    None => {
+                        stack.frames.pop();
+                    }
+                }
+            }
+
+            _ => {}
+        }
+
+        if stack.frames.is_empty() {
+            stack.frames.push(Frame {
+                function: block_key,
+                return_address: None,
+            });
+        }
+
+        let current_function = stack.frames.last().unwrap().function;
+        let function = self
+            .functions
+            .entry(current_function)
+            .or_insert_with(|| Function::new(current_function));
+        function.basic_blocks.insert(basic_block.program_counter);
+        function.basic_block_occurrences += 1;
+        function.executions += basic_block.executions();
+
+        stack.pending_terminator = Some(terminator);
+    }
+
+    // functions sorted from the hottest one
+    pub fn functions(&self) -> Vec<&Function> {
+        let mut functions: Vec<_> = self.functions.values().collect();
+        functions.sort_by(|f0, f1| {
+            (f1.executions, f1.basic_block_occurrences)
+                .cmp(&(f0.executions, f0.basic_block_occurrences))
+                .then(f0.entry.cmp(&f1.entry))
+        });
+        functions
+    }
+}
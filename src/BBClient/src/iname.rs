@@ -1,11 +1,15 @@
 use std::{
     collections::{HashMap, HashSet},
+    ffi::CStr,
     mem,
+    os::raw::c_char,
+    sync::Once,
 };
 
 use lazy_static::lazy_static;
 use maplit::hashmap;
-use redis::{cmd, Client, Commands, Connection};
+use serde_derive::{Deserialize, Serialize};
+use strum::AsStaticRef;
 
 // use xedsys as intel;
 // use crate::intel;
@@ -16,7 +20,11 @@ use xedsys::{
     xed_machine_mode_enum_t, xed_address_width_enum_t,
 };
 
-use crate::{args::ExecutionMode, error::Result};
+use crate::{
+    args::{ExecutionMode, NamingScheme},
+    error::Result,
+    storage::Storage,
+};
 
 macro_rules! ref_to_raw_pointer {
     ($ref_v:expr) => {
@@ -104,18 +112,122 @@ lazy_static! {
     };
 }
 
+fn xed_str(s: *const c_char) -> String {
+    unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned()
+}
+
+// what XED knows about a decoded instruction, stored along with each cached instruction
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct InstructionInfo {
+    pub iclass: String,
+    pub iform: String,
+    pub category: String,
+    pub extension: String,
+    pub isa_set: String,
+    pub operand_width: u32,
+    // in bits, in the operand order of XED
+    pub operand_widths: Vec<u32>,
+    pub privileged: bool,
+}
+
+impl InstructionInfo {
+    fn from_decoded_inst(decoded_inst: &xed_decoded_inst_t) -> Self {
+        let inst_base = decoded_inst_inst(decoded_inst).unwrap();
+        let iform = inst_iform_enum(inst_base);
+
+        let operand_widths = (0..inst_noperands(inst_base))
+            .map(|i| unsafe { xed_decoded_inst_operand_length_bits(decoded_inst, i) })
+            .collect();
+
+        InstructionInfo {
+            iclass: format!("{}", iclass_str(inst_iclass(inst_base))),
+            iform: format!("{}", iform_str(iform)),
+            category: xed_str(unsafe { xed_category_enum_t2str(xed_iform_to_category(iform)) }),
+            extension: xed_str(unsafe { xed_extension_enum_t2str(xed_iform_to_extension(iform)) }),
+            isa_set: xed_str(unsafe { xed_isa_set_enum_t2str(xed_iform_to_isa_set(iform)) }),
+            operand_width: unsafe { xed_decoded_inst_get_operand_width(decoded_inst) },
+            operand_widths,
+            privileged: unsafe {
+                xed_decoded_inst_get_attribute(decoded_inst, XED_ATTRIBUTE_RING0)
+            } != 0,
+        }
+    }
+}
+
+// name of the remill semantic function of the instruction: its iform without LOCK, suffixed
+// with the operand width if the iform is scalable, and with the register for MOV_SEG/MOV_CR
+fn remill_function_name(decoded_inst: &xed_decoded_inst_t) -> String {
+    let inst_base = decoded_inst_inst(decoded_inst).unwrap();
+
+    let has_lock = unsafe { xed_operand_values_has_lock_prefix(decoded_inst) };
+
+    let iform = {
+        let iform = inst_iform_enum(inst_base);
+        if has_lock != 0 {
+            xed_nolock_iform_map.get(&iform).cloned().unwrap_or(iform)
+        } else {
+            iform
+        }
+    };
+
+    let mut func_name = format!("{}", iform_str(iform));
+
+    let is_scalable = {
+        let sc = unsafe { xed_inst_get_attribute(inst_base, XED_ATTRIBUTE_SCALABLE) };
+        sc != 0
+    };
+
+    if is_scalable {
+        func_name = format!("{}_{}", func_name, unsafe {
+            xed_decoded_inst_get_operand_width(decoded_inst)
+        });
+    }
+
+    match iform {
+        XED_IFORM_MOV_SEG_MEMw
+        | XED_IFORM_MOV_SEG_GPR16
+        | XED_IFORM_MOV_CR_CR_GPR32
+        | XED_IFORM_MOV_CR_CR_GPR64 => format!(
+            "{}_{}",
+            func_name,
+            reg_str(unsafe { xed_decoded_inst_get_reg(decoded_inst, XED_OPERAND_REG0) })
+        ),
+
+        _ => func_name,
+    }
+}
+
 struct XedInst<'a> {
     pub data: &'a [u8],
     pub function_name: String,
     pub machine_mode: ExecutionMode,
+    pub info: InstructionInfo,
 }
 
 impl<'a> XedInst<'a> {
-    pub fn from_instruction_data(data: &'a [u8], mode: ExecutionMode) -> Result<Self> {
+    pub fn from_instruction_data(
+        data: &'a [u8],
+        mode: ExecutionMode,
+        naming: NamingScheme,
+    ) -> Result<Self> {
         // use self::intel::*;
-        unsafe { xed_tables_init() };
 
         let xed_mode = match mode {
+            ExecutionMode::Real16 => xed_state_t {
+                mmode: xed_machine_mode_enum_t::XED_MACHINE_MODE_REAL_16,
+                stack_addr_width: xed_address_width_enum_t::XED_ADDRESS_WIDTH_16b,
+            },
+
+            ExecutionMode::Protected16 => xed_state_t {
+                mmode: xed_machine_mode_enum_t::XED_MACHINE_MODE_LEGACY_16,
+                stack_addr_width: xed_address_width_enum_t::XED_ADDRESS_WIDTH_16b,
+            },
+
+            ExecutionMode::Legacy32 => xed_state_t {
+                mmode: xed_machine_mode_enum_t::XED_MACHINE_MODE_LEGACY_32,
+                stack_addr_width: xed_address_width_enum_t::XED_ADDRESS_WIDTH_32b,
+            },
+
             ExecutionMode::Compat => xed_state_t {
                 mmode: xed_machine_mode_enum_t::XED_MACHINE_MODE_LONG_COMPAT_32,
                 stack_addr_width: xed_address_width_enum_t::XED_ADDRESS_WIDTH_32b,
@@ -149,48 +261,14 @@ impl<'a> XedInst<'a> {
             return Err(application_error!(error_str(decoding_error)));
         }
 
-        let remill_function_name = {
-            let inst_base = decoded_inst_inst(&decoded_inst).unwrap();
-            let iclass = inst_iclass(inst_base);
-
-            format!("{}", iclass_str(iclass))
-
-            // let has_lock = unsafe { xed_operand_values_has_lock_prefix(&decoded_inst) };
-
-            // let iform = {
-            //     let iform = inst_iform_enum(inst_base);
-            //     if has_lock != 0 {
-            //         *xed_nolock_iform_map.get(&iform).unwrap()
-            //     } else {
-            //         iform
-            //     }
-            // };
-
-            // let mut func_name = format!("{}", iform_str(iform));
-
-            // let is_scalable = {
-            //     let sc = unsafe { xed_inst_get_attribute(inst_base, XED_ATTRIBUTE_SCALABLE) };
-            //     sc != 0
-            // };
-
-            // if is_scalable {
-            //     func_name = format!("{}_{}", func_name, unsafe {
-            //         xed_decoded_inst_get_operand_width(&decoded_inst)
-            //     });
-            // }
-
-            // match iform {
-            //     XED_IFORM_MOV_SEG_MEMw
-            //     | XED_IFORM_MOV_SEG_GPR16
-            //     | XED_IFORM_MOV_CR_CR_GPR32
-            //     | XED_IFORM_MOV_CR_CR_GPR64 => format!(
-            //         "{}_{}",
-            //         func_name,
-            //         reg_str(unsafe { xed_decoded_inst_get_reg(&decoded_inst, XED_OPERAND_REG0,) })
-            //     ),
-
-            //     _ => func_name,
-            // }
+        let inst_base = decoded_inst_inst(&decoded_inst).unwrap();
+
+        let function_name = match naming {
+            NamingScheme::Iclass => format!("{}", iclass_str(inst_iclass(inst_base))),
+
+            NamingScheme::Iform => format!("{}", iform_str(inst_iform_enum(inst_base))),
+
+            NamingScheme::Remill => remill_function_name(&decoded_inst),
         };
 
         let decoded_byte_count = decoded_inst_get_length(&decoded_inst) as usize;
@@ -198,16 +276,22 @@ impl<'a> XedInst<'a> {
         Ok(XedInst {
             data: &data[0..decoded_byte_count],
             machine_mode: mode,
-            function_name: remill_function_name,
+            function_name,
+            info: InstructionInfo::from_decoded_inst(&decoded_inst),
         })
     }
 
-    pub fn from_basic_block_data(data: &'a [u8], mode: ExecutionMode) -> Vec<Self> {
+    pub fn from_basic_block_data(
+        data: &'a [u8],
+        mode: ExecutionMode,
+        naming: NamingScheme,
+    ) -> Vec<Self> {
         let mut xed_insts = vec![];
 
         let mut decoded_byte_count = 0usize;
         while decoded_byte_count < data.len() {
-            if let Ok(xed_inst) = XedInst::from_instruction_data(&data[decoded_byte_count..], mode)
+            if let Ok(xed_inst) =
+                XedInst::from_instruction_data(&data[decoded_byte_count..], mode, naming)
             {
                 decoded_byte_count += xed_inst.data.len();
                 xed_insts.push(xed_inst);
@@ -220,38 +304,184 @@ impl<'a> XedInst<'a> {
     }
 }
 
+// infos of the successfully decoded instructions of a basic block
+pub(crate) fn instruction_infos(data: &[u8], mode: ExecutionMode) -> Vec<InstructionInfo> {
+    XedInst::from_basic_block_data(data, mode, NamingScheme::Iclass)
+        .into_iter()
+        .map(|xed_inst| xed_inst.info)
+        .collect()
+}
+
+// names and encodings of the successfully decoded instructions of a basic block
+pub(crate) fn instruction_names(
+    data: &[u8],
+    mode: ExecutionMode,
+    naming: NamingScheme,
+) -> Vec<(String, Vec<u8>)> {
+    XedInst::from_basic_block_data(data, mode, naming)
+        .into_iter()
+        .map(|xed_inst| (xed_inst.function_name, xed_inst.data.to_vec()))
+        .collect()
+}
+
+// an instruction decoded by XED, which can be sent to another thread
+pub(crate) struct DecodedInstruction {
+    pub function_name: String,
+    pub data: Vec<u8>,
+    pub info: InstructionInfo,
+}
+
+pub(crate) fn decode_basic_block(
+    data: &[u8],
+    mode: ExecutionMode,
+    naming: NamingScheme,
+) -> Vec<DecodedInstruction> {
+    XedInst::from_basic_block_data(data, mode, naming)
+        .into_iter()
+        .map(|xed_inst| DecodedInstruction {
+            function_name: xed_inst.function_name,
+            data: xed_inst.data.to_vec(),
+            info: xed_inst.info,
+        })
+        .collect()
+}
+
+static XED_INITIALIZATION: Once = Once::new();
+
+// XED tables are global, they must be initialized once before any decoding, and before
+// decoding on several threads
+pub(crate) fn initialize_xed() {
+    XED_INITIALIZATION.call_once(|| unsafe { xed_tables_init() });
+}
+
 pub(crate) struct RemillCache<'a> {
-    connection: Connection,
+    storage: &'a dyn Storage,
     database: &'a str,
+    // the names of the cached instructions and the index of the next basic block to cache
+    // are stored as well, so that a run resumes where the previous one stopped
+    name_set: &'a str,
+    cursor_key: &'a str,
     cached_names: HashSet<String>,
 }
 
-impl<'a, 'b> RemillCache<'a> {
-    pub fn from_args(redis_server_url: &str, instruction_list_name: &'a str) -> Result<Self> {
-        let client = Client::open(redis_server_url)?;
-        let connection = client.get_connection()?;
+// keys of the state kept along with the cached instructions
+pub(crate) struct RemillCacheState<'a> {
+    pub name_set: &'a str,
+    pub cursor_key: &'a str,
+    // the naming scheme the cache is built with, names of another scheme are not mixed in
+    pub naming_key: &'a str,
+    // the layout of the cached records, lists in another layout are not appended to
+    pub format_key: &'a str,
+}
+
+// layout of the records pushed by `cache_instructions`
+const INSTRUCTION_RECORD_FORMAT: &str = "mode|length|bytes|json";
+
+impl<'a> RemillCache<'a> {
+    pub fn from_args(
+        storage: &'a dyn Storage,
+        instruction_list_name: &'a str,
+        state: RemillCacheState<'a>,
+        naming: NamingScheme,
+    ) -> Result<Self> {
+        let cached_format = storage.value_get(state.format_key)?;
+        let cached_naming = storage.value_get(state.naming_key)?;
+
+        // the record format is stored before anything is cached, a list without it has been
+        // cached in the previous layout (and without a name set, it would get duplicates)
+        match cached_format {
+            Some(ref cached_format)
+                if cached_format[..] != *INSTRUCTION_RECORD_FORMAT.as_bytes() =>
+            {
+                return Err(application_error!(format!(
+                    "instruction list {} is cached in the {} record format, not {}, remove it first",
+                    instruction_list_name,
+                    String::from_utf8_lossy(cached_format),
+                    INSTRUCTION_RECORD_FORMAT
+                )));
+            }
+
+            Some(_) => {}
+
+            None if storage.list_length(instruction_list_name)? > 0 => {
+                return Err(application_error!(format!(
+                    "instruction list {} was cached by a previous version, remove it first",
+                    instruction_list_name
+                )));
+            }
+
+            None => storage.value_set(state.format_key, INSTRUCTION_RECORD_FORMAT.as_bytes())?,
+        }
+
+        match cached_naming {
+            Some(ref cached_naming) if cached_naming[..] != *naming.as_static().as_bytes() => {
+                return Err(application_error!(format!(
+                    "instructions are cached with the {} naming, not {}",
+                    String::from_utf8_lossy(cached_naming),
+                    naming.as_static()
+                )));
+            }
+
+            Some(_) => {}
+
+            None => storage.value_set(state.naming_key, naming.as_static().as_bytes())?,
+        }
+
+        let cached_names = storage
+            .set_members(state.name_set)?
+            .into_iter()
+            .map(|name| String::from_utf8_lossy(&name).into_owned())
+            .collect();
+
         Ok(RemillCache {
-            connection,
+            storage,
             database: instruction_list_name,
-            cached_names: HashSet::new(),
+            name_set: state.name_set,
+            cursor_key: state.cursor_key,
+            cached_names,
         })
     }
 
-    pub fn cache_basic_block(&mut self, data: &'b [u8], mode: ExecutionMode) -> Result<()> {
-        let numeric_mode: u8 = match mode {
-            ExecutionMode::Compat => 0,
-            ExecutionMode::Bit64 => 1,
-            _ => unreachable!(),
-        };
-        let xed_insts = XedInst::from_basic_block_data(data, mode);
+    // index of the next basic block to cache
+    pub fn cursor(&self) -> Result<usize> {
+        match self.storage.value_get(self.cursor_key)? {
+            Some(cursor) => String::from_utf8_lossy(&cursor)
+                .parse::<usize>()
+                .map_err(|_| application_error!("bad instruction cache cursor")),
 
-        for xed_inst in xed_insts {
-            if !self.cached_names.contains(&xed_inst.function_name) {
-                let mut cache_data = vec![numeric_mode];
-                cache_data.extend(xed_inst.data);
-                self.connection.rpush(self.database, cache_data)?;
+            None => Ok(0),
+        }
+    }
+
+    pub fn save_cursor(&self, cursor: usize) -> Result<()> {
+        self.storage
+            .value_set(self.cursor_key, cursor.to_string().as_bytes())
+    }
 
-                self.cached_names.insert(xed_inst.function_name);
+    // instructions of a basic block, as decoded by `decode_basic_block`, are deduplicated by
+    // their function name; a record holds the execution mode, the length of the instruction,
+    // its bytes, then its info in JSON
+    pub fn cache_instructions(
+        &mut self,
+        instructions: Vec<DecodedInstruction>,
+        mode: ExecutionMode,
+    ) -> Result<()> {
+        let numeric_mode = u8::from(mode);
+
+        for instruction in instructions {
+            if !self.cached_names.contains(&instruction.function_name) {
+                // pushed before the name is added to the set, so that a run interrupted in
+                // between never loses the instruction (at worst, it is pushed again once
+                // resumed from the cursor)
+                let mut cache_data = vec![numeric_mode, instruction.data.len() as u8];
+                cache_data.extend(instruction.data);
+                serde_json::to_writer(&mut cache_data, &instruction.info)?;
+                self.storage.list_push(self.database, &cache_data)?;
+
+                self.storage
+                    .set_add(self.name_set, instruction.function_name.as_bytes())?;
+
+                self.cached_names.insert(instruction.function_name);
             }
         }
 
@@ -259,6 +489,6 @@ impl<'a, 'b> RemillCache<'a> {
     }
 
     pub fn count(&mut self) -> Result<usize> {
-        self.connection.llen(self.database).map_err(From::from)
+        self.storage.list_length(self.database)
     }
 }
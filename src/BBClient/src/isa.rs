@@ -0,0 +1,102 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::Write,
+};
+
+use fasthash::metro;
+use lru::LruCache;
+use tabwriter::TabWriter;
+
+use crate::{
+    args::ExecutionMode,
+    cache::BasicBlock,
+    error::Result,
+    iname::{self, InstructionInfo},
+};
+
+#[derive(Default)]
+struct ExtensionCount {
+    iforms: BTreeSet<String>,
+    privileged_iforms: BTreeSet<String>,
+    instructions: u64,
+    executions: u64,
+}
+
+// ISA extensions of the instructions in the trace, weighted by the executions of their
+// basic blocks
+pub(crate) struct IsaCollector {
+    // recently seen basic blocks are not decoded by XED again
+    decoded_basic_blocks: LruCache<(ExecutionMode, u64), Vec<InstructionInfo>>,
+    extensions: HashMap<String, ExtensionCount>,
+}
+
+impl IsaCollector {
+    pub fn new() -> Self {
+        iname::initialize_xed();
+
+        IsaCollector {
+            decoded_basic_blocks: LruCache::new(16 * 1024),
+            extensions: HashMap::new(),
+        }
+    }
+
+    pub fn add_basic_block(&mut self, basic_block: &BasicBlock) {
+        let executions = basic_block.executions();
+        let execution_mode = basic_block.execution_mode;
+
+        let basic_block_hash = (execution_mode, metro::hash64(&basic_block.data));
+        if self.decoded_basic_blocks.get(&basic_block_hash).is_none() {
+            self.decoded_basic_blocks.put(
+                basic_block_hash,
+                iname::instruction_infos(&basic_block.data, execution_mode),
+            );
+        }
+
+        let instruction_infos = self.decoded_basic_blocks.get(&basic_block_hash).unwrap();
+        for info in instruction_infos.iter() {
+            let extension = self
+                .extensions
+                .entry(info.extension.clone())
+                .or_insert_with(ExtensionCount::default);
+            if !extension.iforms.contains(&info.iform) {
+                extension.iforms.insert(info.iform.clone());
+                if info.privileged {
+                    extension.privileged_iforms.insert(info.iform.clone());
+                }
+            }
+            extension.instructions += 1;
+            extension.executions += executions;
+        }
+    }
+
+    pub fn write_table<W: Write>(&self, output: &mut W) -> Result<()> {
+        let mut extensions: Vec<_> = self.extensions.iter().collect();
+        extensions.sort_by(|(name0, count0), (name1, count1)| {
+            count1
+                .executions
+                .cmp(&count0.executions)
+                .then(name0.cmp(name1))
+        });
+
+        let mut tw = TabWriter::new(output).padding(4);
+
+        writeln!(
+            tw,
+            "extension\tiforms\tprivileged iforms\tinstructions\texecutions"
+        )?;
+        for (name, count) in extensions {
+            writeln!(
+                tw,
+                "{}\t{}\t{}\t{}\t{}",
+                name,
+                count.iforms.len(),
+                count.privileged_iforms.len(),
+                count.instructions,
+                count.executions
+            )?;
+        }
+        tw.flush()?;
+
+        Ok(())
+    }
+}
@@ -1,6 +1,15 @@
+use fasthash::metro;
 use indicatif::ProgressBar;
-use std::io::{self, Write};
+use lru::LruCache;
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs::File,
+    io::{self, BufWriter, Write},
+    mem,
+    path::Path,
+};
 use structopt::StructOpt;
+use strum::AsStaticRef;
 use tabwriter::TabWriter;
 
 // use xed_sys as intel;
@@ -8,89 +17,1173 @@ use tabwriter::TabWriter;
 #[macro_use]
 mod error;
 mod args;
+mod cfg;
+mod coverage;
 mod disasm;
+mod drcov;
+mod function;
 // mod ui;
 mod cache;
 mod iname;
+mod isa;
+mod module;
+mod pipeline;
+mod query;
+mod sequence;
+mod stats;
+mod storage;
+mod symbol;
+mod trace;
+mod transition;
+mod verify;
 
 // use crate::cache::Cache;
 
-use crate::error::Result;
+use crate::error::{Error, RecordError, Result};
 
-const REDIS_SERVER_LOCATION: &str = "redis://localhost";
 const RAW_BASIC_BLOCK_LIST: &str = "raw_basic_block_list";
 const ADDRESS_INDEPENDENT_BASIC_BLOCK_LIST: &str = "address_independent_basic_block_list";
 const BASIC_BLOCK_LIST: &str = "basic_block_list";
 const INSTRUCTION_LIST: &str = "instruction_list";
+const INSTRUCTION_NAME_SET: &str = "instruction_name_set";
+const INSTRUCTION_CACHE_CURSOR: &str = "instruction_cache_cursor";
+const INSTRUCTION_CACHE_NAMING: &str = "instruction_cache_naming";
+const INSTRUCTION_CACHE_FORMAT: &str = "instruction_cache_format";
+const DECODING_BATCH_SIZE: usize = 256;
+const TRACE_NAMESPACE_SEPARATOR: &str = ":";
+
+// names of the cached lists of a trace (and of the state kept along with them), namespaced
+// by the trace name if there is one
+struct TraceLists {
+    raw_basic_block_list: String,
+    basic_block_list: String,
+    address_independent_basic_block_list: String,
+    instruction_list: String,
+    instruction_name_set: String,
+    instruction_cache_cursor: String,
+    instruction_cache_naming: String,
+    instruction_cache_format: String,
+}
+
+impl TraceLists {
+    fn from_trace_name(trace_name: Option<&str>) -> Self {
+        let namespaced = |list: &str| {
+            if let Some(trace_name) = trace_name {
+                format!("{}{}{}", trace_name, TRACE_NAMESPACE_SEPARATOR, list)
+            } else {
+                list.to_owned()
+            }
+        };
+
+        TraceLists {
+            raw_basic_block_list: namespaced(RAW_BASIC_BLOCK_LIST),
+            basic_block_list: namespaced(BASIC_BLOCK_LIST),
+            address_independent_basic_block_list: namespaced(ADDRESS_INDEPENDENT_BASIC_BLOCK_LIST),
+            instruction_list: namespaced(INSTRUCTION_LIST),
+            instruction_name_set: namespaced(INSTRUCTION_NAME_SET),
+            instruction_cache_cursor: namespaced(INSTRUCTION_CACHE_CURSOR),
+            instruction_cache_naming: namespaced(INSTRUCTION_CACHE_NAMING),
+            instruction_cache_format: namespaced(INSTRUCTION_CACHE_FORMAT),
+        }
+    }
+}
+
+// symbols and modules labelling the addresses in the outputs
+struct AddressLabels {
+    symbols: symbol::SymbolMap,
+    module_map: module::ModuleMap,
+}
+
+impl AddressLabels {
+    // " [ntdll+0x1c]" if the address is in a module of the map
+    fn module_label(&self, address: u64) -> String {
+        self.module_map
+            .tag(address)
+            .map_or_else(String::new, |tag| format!(" [{}]", tag))
+    }
+
+    // " <func+0x1c>" if the address has a symbol
+    fn symbol_label(&self, address: u64) -> String {
+        self.symbols
+            .symbolize(address)
+            .map_or_else(String::new, |symbol| format!(" <{}>", symbol))
+    }
+}
 
 fn main() -> Result<()> {
     let opt = args::Opt::from_args();
 
-    let cache = cache::Cache::from_url(REDIS_SERVER_LOCATION)?;
+    let mut cache = if let Some(ref store_directory) = opt.store_directory {
+        cache::Cache::from_directory(store_directory)?
+    } else {
+        cache::Cache::from_url(&opt.redis_url, opt.database)?
+    };
+    cache.set_batch_size(opt.batch_size);
+    cache.set_bad_record_policy(opt.bad_record_policy);
 
-    if let Some(opt) = args::ShowingClientOpt::from(opt) {
-        let stdout = io::stdout();
-        let mut tw = TabWriter::new(stdout.lock()).padding(4);
+    let lists = TraceLists::from_trace_name(opt.trace_name.as_ref().map(String::as_str));
 
-        let mut disasm = disasm::Disasm::from_args()?;
+    let labels = AddressLabels {
+        symbols: symbol::SymbolMap::from_sources(&opt.symbol_sources)?,
+        module_map: match opt.module_map_file {
+            Some(ref module_map_file) => module::ModuleMap::from_file(module_map_file)?,
 
-        let basic_blocks = cache.basic_blocks::<cache::BasicBlock>(BASIC_BLOCK_LIST)?;
+            None => module::ModuleMap::default(),
+        },
+    };
 
-        for (basic_block_index, basic_block) in basic_blocks
-            .skip(opt.starting_index)
-            .enumerate()
-            .filter(|(_, bb)| {
-                if let Some(exec_mode) = opt.execution_mode {
-                    bb.execution_mode == exec_mode
-                } else {
-                    true
-                }
-            })
-            .filter(|(_, bb)| {
-                if let Some(exec_ring) = opt.execution_privilege {
-                    bb.execution_privilege == exec_ring
-                } else {
-                    true
-                }
-            }) {
+    match opt.command {
+        args::Command::CacheOpt {
+            naming,
+            restart,
+            jobs,
+        } => cache_instructions(&cache, &lists, naming, restart, jobs),
+
+        args::Command::CfgOpt {
+            format,
+            output_file,
+        } => build_cfg(
+            &cache,
+            &lists,
+            format,
+            output_file.as_ref().map(|f| f.as_path()),
+            &labels,
+        ),
+
+        args::Command::FunctionsOpt { top } => recover_functions(&cache, &lists, top, &labels),
+
+        args::Command::StatsOpt { top, json } => {
+            compute_statistics(&cache, &lists, top, json, &labels)
+        }
+
+        args::Command::ExportOpt {
+            format,
+            output_file,
+            module_map_file,
+        } => {
+            let module_map = match module_map_file {
+                Some(ref module_map_file) => module::ModuleMap::from_file(module_map_file)?,
+
+                None => labels.module_map,
+            };
+            export_coverage(
+                &cache,
+                &lists,
+                format,
+                &module_map,
+                output_file.as_ref().map(|f| f.as_path()),
+            )
+        }
+
+        args::Command::ListTracesOpt {} => list_traces(&cache),
+
+        args::Command::IngestOpt {
+            trace_file,
+            metadata_file,
+        } => ingest_trace(&cache, &lists, &trace_file, &metadata_file),
+
+        args::Command::SequenceOpt {
+            patterns,
+            window_size,
+            starting_index,
+            formatter_profile,
+        } => find_sequences(
+            &cache,
+            &lists,
+            patterns,
+            window_size,
+            starting_index,
+            &formatter_profile,
+            &labels,
+        ),
+
+        args::Command::TransitionsOpt { summary_only } => {
+            find_transitions(&cache, &lists, summary_only, &labels)
+        }
+
+        args::Command::ModeSwitchesOpt {} => find_mode_switches(&cache, &lists, &labels),
+
+        args::Command::IsaReportOpt {} => report_isa_extensions(&cache, &lists),
+
+        args::Command::CoverageOpt {
+            supported_file,
+            naming,
+        } => check_coverage(&cache, &lists, &supported_file, naming),
+
+        args::Command::VerifyOpt {} => verify_basic_blocks(&cache, &lists, &labels.module_map),
+
+        command => match args::ShowingClientOpt::from(command) {
+            Some(opt) => show_basic_blocks(&cache, &lists, &opt, &labels),
+
+            None => unreachable!(),
+        },
+    }
+}
+
+fn show_basic_blocks(
+    cache: &cache::Cache,
+    lists: &TraceLists,
+    opt: &args::ShowingClientOpt,
+    labels: &AddressLabels,
+) -> Result<()> {
+    if let Some(ref module_name) = opt.module {
+        if !labels.module_map.contains_module(module_name) {
+            return Err(application_error!(format!(
+                "module {} is not in the module map",
+                module_name
+            )));
+        }
+    }
+
+    let stdout = io::stdout();
+    let mut tw = TabWriter::new(stdout.lock()).padding(4);
+
+    let mut disasm = disasm::Disasm::from_args(&opt.formatter_profile)?;
+
+    let basic_blocks =
+        cache.basic_blocks::<cache::BasicBlock>(&lists.basic_block_list, opt.starting_index)?;
+
+    for basic_block in basic_blocks {
+        let (basic_block_index, basic_block) = basic_block?;
+
+        if let Some(exec_mode) = opt.execution_mode {
+            if basic_block.execution_mode != exec_mode {
+                continue;
+            }
+        }
+
+        if let Some(exec_ring) = opt.execution_privilege {
+            if basic_block.execution_privilege != exec_ring {
+                continue;
+            }
+        }
+
+        if let Some(ref module_name) = opt.module {
+            match labels.module_map.locate(basic_block.program_counter) {
+                Some((module, _)) if module.name == *module_name => {}
+
+                _ => continue,
+            }
+        }
+
+        if let Some(ref byte_pattern) = opt.byte_pattern {
+            if !byte_pattern.found_in(&basic_block.data) {
+                continue;
+            }
+        }
+
+        let mut disasm_basic_block = disasm.disasm(
+            &basic_block.data,
+            basic_block.execution_mode,
+            Some(basic_block.program_counter),
+        )?;
+
+        if let Some(ref ins_pattern) = opt.instruction_pattern {
+            if !disasm_basic_block.contain_instruction_pattern(ins_pattern) {
+                continue;
+            }
+        }
+
+        if let Some(address) = opt.address {
+            if !disasm_basic_block.cover_address(address) {
+                continue;
+            }
+        }
+
+        if let Some((begin_address, end_address)) = opt.address_range {
+            if !disasm_basic_block.overlap_address_range(begin_address, end_address) {
+                continue;
+            }
+        }
+
+        disasm_basic_block.symbolize_targets(&labels.symbols);
+
+        writeln!(
+            tw,
+            "basic block: {} ({}){}{}",
+            basic_block_index,
+            basic_block,
+            labels.module_label(basic_block.program_counter),
+            labels.symbol_label(basic_block.program_counter)
+        )?;
+        writeln!(tw, "\n{}\n", disasm_basic_block)?;
+        tw.flush()?;
+    }
+
+    Ok(())
+}
+
+fn find_sequences(
+    cache: &cache::Cache,
+    lists: &TraceLists,
+    patterns: Vec<query::Query>,
+    window_size: usize,
+    starting_index: usize,
+    formatter_profile: &args::FormatterProfile,
+    labels: &AddressLabels,
+) -> Result<()> {
+    let stdout = io::stdout();
+    let mut tw = TabWriter::new(stdout.lock()).padding(4);
+
+    let mut disasm = disasm::Disasm::from_args(formatter_profile)?;
+
+    let mut matcher = sequence::SequenceMatcher::new(patterns, window_size)?;
+
+    let basic_blocks =
+        cache.basic_blocks::<cache::BasicBlock>(&lists.basic_block_list, starting_index)?;
+
+    for basic_block in basic_blocks {
+        let (basic_block_index, basic_block) = basic_block?;
+
+        let pattern_matches = {
             let disasm_basic_block = disasm.disasm(
                 &basic_block.data,
                 basic_block.execution_mode,
                 Some(basic_block.program_counter),
             )?;
+            matcher.match_patterns(&disasm_basic_block)
+        };
 
-            if let Some(ref ins_pattern) = opt.instruction_pattern {
-                if !disasm_basic_block.contain_instruction_pattern(&ins_pattern) {
-                    continue;
-                }
-            }
+        let sequence_match = match matcher.push(basic_block_index, basic_block, pattern_matches) {
+            Some(sequence_match) => sequence_match,
+
+            None => continue,
+        };
+
+        writeln!(
+            tw,
+            "sequence: {} -> {}",
+            sequence_match.matched_indices[0],
+            sequence_match.matched_indices[sequence_match.matched_indices.len() - 1]
+        )?;
+        for (basic_block_index, basic_block) in &sequence_match.basic_blocks {
+            let mut disasm_basic_block = disasm.disasm(
+                &basic_block.data,
+                basic_block.execution_mode,
+                Some(basic_block.program_counter),
+            )?;
+            disasm_basic_block.symbolize_targets(&labels.symbols);
 
+            // matched basic blocks are marked
+            let marker = if sequence_match.matched_indices.contains(basic_block_index) {
+                "*"
+            } else {
+                " "
+            };
             writeln!(
                 tw,
-                "basic block: {} ({})",
-                basic_block_index + opt.starting_index,
-                basic_block
-            );
-            writeln!(tw, "\n{}\n", disasm_basic_block);
-            tw.flush()?;
+                "{} basic block: {} ({}){}{}",
+                marker,
+                basic_block_index,
+                basic_block,
+                labels.module_label(basic_block.program_counter),
+                labels.symbol_label(basic_block.program_counter)
+            )?;
+            writeln!(tw, "\n{}\n", disasm_basic_block)?;
+        }
+        tw.flush()?;
+    }
+
+    Ok(())
+}
+
+fn find_transitions(
+    cache: &cache::Cache,
+    lists: &TraceLists,
+    summary_only: bool,
+    labels: &AddressLabels,
+) -> Result<()> {
+    let mut disasm = disasm::Disasm::from_args(&args::FormatterProfile::default())?;
+
+    let basic_blocks = cache.basic_blocks::<cache::BasicBlock>(&lists.basic_block_list, 0)?;
+
+    let progress_bar = ProgressBar::new(basic_blocks.count as u64);
+
+    let mut transition_collector = transition::TransitionCollector::new();
+    let mut previous_basic_block: Option<(usize, cache::BasicBlock)> = None;
+    for basic_block in basic_blocks {
+        let (basic_block_index, basic_block) = basic_block?;
+
+        if let Some((previous_index, previous_basic_block)) = &previous_basic_block {
+            if let Some(direction) = transition::Direction::between(
+                previous_basic_block.execution_privilege,
+                basic_block.execution_privilege,
+            ) {
+                let cause = {
+                    let disasm_basic_block = disasm.disasm(
+                        &previous_basic_block.data,
+                        previous_basic_block.execution_mode,
+                        Some(previous_basic_block.program_counter),
+                    )?;
+                    transition::Cause::from_basic_block(&disasm_basic_block, direction)
+                };
+
+                transition_collector.add_transition(transition::Transition::new(
+                    (*previous_index, previous_basic_block),
+                    (basic_block_index, &basic_block),
+                    direction,
+                    cause,
+                ));
+            }
+        }
+
+        previous_basic_block = Some((basic_block_index, basic_block));
+
+        progress_bar.set_position(basic_block_index as u64 + 1);
+    }
+
+    progress_bar.finish_and_clear();
+
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    transition_collector.write_table(
+        &mut output,
+        summary_only,
+        &labels.symbols,
+        &labels.module_map,
+    )
+}
+
+fn find_mode_switches(
+    cache: &cache::Cache,
+    lists: &TraceLists,
+    labels: &AddressLabels,
+) -> Result<()> {
+    let mut disasm = disasm::Disasm::from_args(&args::FormatterProfile::default())?;
+
+    let basic_blocks = cache.basic_blocks::<cache::BasicBlock>(&lists.basic_block_list, 0)?;
+
+    let progress_bar = ProgressBar::new(basic_blocks.count as u64);
+
+    let mut mode_switches = vec![];
+    let mut previous_basic_block: Option<(usize, cache::BasicBlock)> = None;
+    for basic_block in basic_blocks {
+        let (basic_block_index, basic_block) = basic_block?;
+
+        if let Some((previous_index, previous_basic_block)) = &previous_basic_block {
+            if transition::ModeSwitch::is_switch(previous_basic_block, &basic_block) {
+                let disasm_basic_block = disasm.disasm(
+                    &previous_basic_block.data,
+                    previous_basic_block.execution_mode,
+                    Some(previous_basic_block.program_counter),
+                )?;
+
+                mode_switches.push(transition::ModeSwitch::new(
+                    (*previous_index, previous_basic_block),
+                    (basic_block_index, &basic_block),
+                    &disasm_basic_block,
+                ));
+            }
+        }
+
+        previous_basic_block = Some((basic_block_index, basic_block));
+
+        progress_bar.set_position(basic_block_index as u64 + 1);
+    }
+
+    progress_bar.finish_and_clear();
+
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    transition::write_mode_switches(
+        &mode_switches,
+        &mut output,
+        &labels.symbols,
+        &labels.module_map,
+    )
+}
+
+fn report_isa_extensions(cache: &cache::Cache, lists: &TraceLists) -> Result<()> {
+    let basic_blocks = cache.basic_blocks::<cache::BasicBlock>(&lists.basic_block_list, 0)?;
+
+    let progress_bar = ProgressBar::new(basic_blocks.count as u64);
+
+    let mut isa_collector = isa::IsaCollector::new();
+    for basic_block in basic_blocks {
+        let (basic_block_index, basic_block) = basic_block?;
+        isa_collector.add_basic_block(&basic_block);
+        progress_bar.set_position(basic_block_index as u64 + 1);
+    }
+
+    progress_bar.finish_and_clear();
+
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    isa_collector.write_table(&mut output)
+}
+
+fn check_coverage(
+    cache: &cache::Cache,
+    lists: &TraceLists,
+    supported_file: &Path,
+    naming: args::NamingScheme,
+) -> Result<()> {
+    let supported_names = coverage::read_supported_names(supported_file)?;
+
+    let basic_blocks = cache.basic_blocks::<cache::BasicBlock>(&lists.basic_block_list, 0)?;
+
+    let progress_bar = ProgressBar::new(basic_blocks.count as u64);
+
+    let mut coverage_collector = coverage::CoverageCollector::new(naming);
+    for basic_block in basic_blocks {
+        let (basic_block_index, basic_block) = basic_block?;
+        coverage_collector.add_basic_block(&basic_block);
+        progress_bar.set_position(basic_block_index as u64 + 1);
+    }
+
+    progress_bar.finish_and_clear();
+
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    let missing_count = coverage_collector.write_missing(&supported_names, &mut output)?;
+    writeln!(output, "\n{} instruction(s) not supported", missing_count)?;
+
+    Ok(())
+}
+
+fn verify_basic_blocks(
+    cache: &cache::Cache,
+    lists: &TraceLists,
+    module_map: &module::ModuleMap,
+) -> Result<()> {
+    let mut verifier = verify::Verifier::new(module_map)?;
+
+    let basic_blocks = cache.basic_blocks::<cache::BasicBlock>(&lists.basic_block_list, 0)?;
+
+    let progress_bar = ProgressBar::new(basic_blocks.count as u64);
+
+    for basic_block in basic_blocks {
+        let (basic_block_index, basic_block) = basic_block?;
+        verifier.add_basic_block(basic_block_index, &basic_block);
+        progress_bar.set_position(basic_block_index as u64 + 1);
+    }
+
+    progress_bar.finish_and_clear();
+
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    verifier.write_mismatches(&mut output)?;
+
+    Ok(())
+}
+
+fn cache_instructions(
+    cache: &cache::Cache,
+    lists: &TraceLists,
+    naming: args::NamingScheme,
+    restart: bool,
+    jobs: usize,
+) -> Result<()> {
+    let mut instruction_cache = iname::RemillCache::from_args(
+        cache.storage(),
+        &lists.instruction_list,
+        iname::RemillCacheState {
+            name_set: &lists.instruction_name_set,
+            cursor_key: &lists.instruction_cache_cursor,
+            naming_key: &lists.instruction_cache_naming,
+            format_key: &lists.instruction_cache_format,
+        },
+        naming,
+    )?;
+
+    let starting_index = if restart {
+        0
+    } else {
+        instruction_cache.cursor()?
+    };
+
+    let basic_blocks = cache.basic_blocks::<cache::AddressIndependentBasicBlock>(
+        &lists.address_independent_basic_block_list,
+        starting_index,
+    )?;
+
+    let basic_block_count = basic_blocks.count;
+    let progress_bar = ProgressBar::new(basic_block_count as u64);
+    progress_bar.set_position(starting_index as u64);
+
+    // basic blocks are read and decoded instructions are cached on this thread, in the order
+    // of the list, while the workers decode
+    let mut decoder_pool = pipeline::DecoderPool::new(jobs, naming);
+
+    let mut batch = Vec::with_capacity(DECODING_BATCH_SIZE);
+    for basic_block in basic_blocks {
+        let (basic_block_index, basic_block) = basic_block?;
+        batch.push(pipeline::BasicBlockJob {
+            index: basic_block_index,
+            execution_mode: basic_block.execution_mode,
+            data: basic_block.data,
+        });
+
+        if batch.len() == DECODING_BATCH_SIZE {
+            decoder_pool.submit(mem::replace(
+                &mut batch,
+                Vec::with_capacity(DECODING_BATCH_SIZE),
+            ))?;
+
+            while let Some(decoded_batch) = decoder_pool.try_receive() {
+                cache_decoded_basic_blocks(&mut instruction_cache, decoded_batch, &progress_bar)?;
+            }
         }
+    }
+
+    if !batch.is_empty() {
+        decoder_pool.submit(batch)?;
+    }
+
+    while let Some(decoded_batch) = decoder_pool.receive()? {
+        cache_decoded_basic_blocks(&mut instruction_cache, decoded_batch, &progress_bar)?;
+    }
+
+    progress_bar.finish_and_clear();
+
+    instruction_cache.save_cursor(basic_block_count.max(starting_index))?;
+
+    println!("{} instruction cached", instruction_cache.count()?);
+
+    Ok(())
+}
+
+// the cursor is saved after each batch, so that an interrupted run resumes from there
+fn cache_decoded_basic_blocks(
+    instruction_cache: &mut iname::RemillCache,
+    decoded_batch: Vec<pipeline::DecodedBasicBlock>,
+    progress_bar: &ProgressBar,
+) -> Result<()> {
+    let mut cursor = None;
+    for decoded_basic_block in decoded_batch {
+        instruction_cache.cache_instructions(
+            decoded_basic_block.instructions,
+            decoded_basic_block.execution_mode,
+        )?;
+        cursor = Some(decoded_basic_block.index + 1);
+    }
+
+    if let Some(cursor) = cursor {
+        instruction_cache.save_cursor(cursor)?;
+        progress_bar.set_position(cursor as u64);
+    }
+
+    Ok(())
+}
+
+fn output_writer(output_file: Option<&Path>) -> Result<Box<dyn Write>> {
+    if let Some(output_file) = output_file {
+        Ok(Box::new(BufWriter::new(File::create(output_file)?)))
     } else {
-        let basic_blocks = cache.basic_blocks::<cache::AddressIndependentBasicBlock>(
-            ADDRESS_INDEPENDENT_BASIC_BLOCK_LIST,
+        Ok(Box::new(io::stdout()))
+    }
+}
+
+fn build_cfg(
+    cache: &cache::Cache,
+    lists: &TraceLists,
+    format: args::GraphFormat,
+    output_file: Option<&Path>,
+    labels: &AddressLabels,
+) -> Result<()> {
+    let basic_blocks = cache.basic_blocks::<cache::BasicBlock>(&lists.basic_block_list, 0)?;
+
+    let progress_bar = ProgressBar::new(basic_blocks.count as u64);
+
+    let mut cfg = cfg::ControlFlowGraph::new();
+    for basic_block in basic_blocks {
+        let (basic_block_index, basic_block) = basic_block?;
+        cfg.add_basic_block(&basic_block);
+        progress_bar.set_position(basic_block_index as u64 + 1);
+    }
+
+    progress_bar.finish_and_clear();
+    eprintln!("{} nodes, {} edges", cfg.node_count(), cfg.edge_count());
+    cfg.symbolize(&labels.symbols, &labels.module_map);
+
+    let mut output = output_writer(output_file)?;
+    match format {
+        args::GraphFormat::Dot => cfg.write_dot(&mut output)?,
+
+        args::GraphFormat::Json => cfg.write_json(&mut output)?,
+    }
+    output.flush()?;
+
+    Ok(())
+}
+
+fn recover_functions(
+    cache: &cache::Cache,
+    lists: &TraceLists,
+    top: Option<usize>,
+    labels: &AddressLabels,
+) -> Result<()> {
+    let mut disasm = disasm::Disasm::from_args(&args::FormatterProfile::default())?;
+
+    let basic_blocks = cache.basic_blocks::<cache::BasicBlock>(&lists.basic_block_list, 0)?;
+
+    let progress_bar = ProgressBar::new(basic_blocks.count as u64);
+
+    let mut call_tree = function::CallTree::new();
+    for basic_block in basic_blocks {
+        let (basic_block_index, basic_block) = basic_block?;
+
+        let terminator = {
+            let disasm_basic_block = disasm.disasm(
+                &basic_block.data,
+                basic_block.execution_mode,
+                Some(basic_block.program_counter),
+            )?;
+            function::Terminator::from_basic_block(&disasm_basic_block)
+        };
+        call_tree.add_basic_block(&basic_block, terminator);
+
+        progress_bar.set_position(basic_block_index as u64 + 1);
+    }
+
+    progress_bar.finish_and_clear();
+
+    let stdout = io::stdout();
+    let mut tw = TabWriter::new(stdout.lock()).padding(4);
+
+    writeln!(
+        tw,
+        "entry\tmodule\tsymbol\tmode\tcalls\tcall sites\tcallees\tbasic blocks\toccurrences\t\
+         executions"
+    )?;
+    let functions = call_tree.functions();
+    for function in functions
+        .iter()
+        .take(top.unwrap_or_else(|| functions.len()))
+    {
+        writeln!(
+            tw,
+            "0x{:016x}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            function.entry,
+            labels
+                .module_map
+                .tag(function.entry)
+                .unwrap_or_else(|| "-".to_owned()),
+            labels
+                .symbols
+                .symbolize(function.entry)
+                .unwrap_or_else(|| "-".to_owned()),
+            function.execution_mode.as_static(),
+            function.call_count,
+            function.call_sites.len(),
+            function.callees.len(),
+            function.basic_blocks.len(),
+            function.basic_block_occurrences,
+            function.executions
         )?;
+    }
+    tw.flush()?;
 
-        let mut instruction_cache =
-            iname::RemillCache::from_args(REDIS_SERVER_LOCATION, INSTRUCTION_LIST)?;
+    Ok(())
+}
+
+fn compute_statistics(
+    cache: &cache::Cache,
+    lists: &TraceLists,
+    top: usize,
+    json: bool,
+    labels: &AddressLabels,
+) -> Result<()> {
+    let basic_blocks = cache.basic_blocks::<cache::BasicBlock>(&lists.basic_block_list, 0)?;
+
+    let progress_bar = ProgressBar::new(basic_blocks.count as u64);
+
+    let mut statistics_collector = stats::StatisticsCollector::new(&labels.module_map);
+    for basic_block in basic_blocks {
+        let (basic_block_index, basic_block) = basic_block?;
+        statistics_collector.add_basic_block(&basic_block);
+        progress_bar.set_position(basic_block_index as u64 + 1);
+    }
+
+    progress_bar.finish_and_clear();
 
-        let progress_bar = ProgressBar::new(basic_blocks.count as u64);
+    let mut statistics = statistics_collector.finish(
+        top,
+        cache.count(&lists.raw_basic_block_list)?,
+        cache.count(&lists.address_independent_basic_block_list)?,
+    );
+    statistics.symbolize(&labels.symbols);
 
-        for basic_block in basic_blocks {
-            instruction_cache.cache_basic_block(&basic_block.data, basic_block.execution_mode)?;
-            progress_bar.inc(1);
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    if json {
+        statistics.write_json(&mut output)
+    } else {
+        statistics.write_table(&mut output)
+    }
+}
+
+fn export_coverage(
+    cache: &cache::Cache,
+    lists: &TraceLists,
+    format: args::ExportFormat,
+    module_map: &module::ModuleMap,
+    output_file: Option<&Path>,
+) -> Result<()> {
+    let basic_blocks = cache.basic_blocks::<cache::BasicBlock>(&lists.basic_block_list, 0)?;
+
+    let progress_bar = ProgressBar::new(basic_blocks.count as u64);
+
+    // coverage only needs unique (start, size) blocks
+    let mut covered_basic_blocks = BTreeSet::new();
+    for basic_block in basic_blocks {
+        let (basic_block_index, basic_block) = basic_block?;
+        covered_basic_blocks.insert((basic_block.program_counter, basic_block.data.len() as u16));
+        progress_bar.set_position(basic_block_index as u64 + 1);
+    }
+
+    progress_bar.finish_and_clear();
+
+    // without a module map, the trace is split into as few modules as the 32-bit block offsets
+    // allow
+    let synthetic_module_map;
+    let module_map = if !module_map.is_empty() {
+        module_map
+    } else {
+        synthetic_module_map = module::ModuleMap::synthetic("trace", &covered_basic_blocks);
+        &synthetic_module_map
+    };
+
+    let mut output = output_writer(output_file)?;
+    let exported_count = match format {
+        args::ExportFormat::Drcov => {
+            drcov::write_drcov(module_map, &covered_basic_blocks, &mut output)?
         }
+    };
+    output.flush()?;
+
+    eprintln!(
+        "{} of {} unique basic blocks exported",
+        exported_count,
+        covered_basic_blocks.len()
+    );
 
-        println!("{} instruction cached", instruction_cache.count()?);
+    Ok(())
+}
+
+fn list_traces(cache: &cache::Cache) -> Result<()> {
+    let stdout = io::stdout();
+    let mut tw = TabWriter::new(stdout.lock()).padding(4);
+
+    let mut trace_names: Vec<_> = cache
+        .storage()
+        .lists()?
+        .into_iter()
+        .filter_map(|list| {
+            if list == BASIC_BLOCK_LIST {
+                Some(None)
+            } else if list.ends_with(&format!(
+                "{}{}",
+                TRACE_NAMESPACE_SEPARATOR, BASIC_BLOCK_LIST
+            )) {
+                let trace_name_length =
+                    list.len() - BASIC_BLOCK_LIST.len() - TRACE_NAMESPACE_SEPARATOR.len();
+                Some(Some(list[..trace_name_length].to_owned()))
+            } else {
+                None
+            }
+        })
+        .collect();
+    trace_names.sort();
+
+    writeln!(tw, "trace\tbasic blocks\tunique basic blocks\tinstructions")?;
+    for trace_name in trace_names {
+        let lists = TraceLists::from_trace_name(trace_name.as_ref().map(String::as_str));
+        writeln!(
+            tw,
+            "{}\t{}\t{}\t{}",
+            trace_name.as_ref().map_or("(default)", String::as_str),
+            cache.count(&lists.basic_block_list)?,
+            cache.count(&lists.address_independent_basic_block_list)?,
+            cache.count(&lists.instruction_list)?
+        )?;
     }
+    tw.flush()?;
 
     Ok(())
 }
+
+fn ingest_trace(
+    cache: &cache::Cache,
+    lists: &TraceLists,
+    trace_file: &Path,
+    metadata_file: &Path,
+) -> Result<()> {
+    // the back-references of a trace index its own raw basic blocks from 0, and its address
+    // independent basic blocks are deduplicated among themselves only
+    for list in &[
+        &lists.raw_basic_block_list,
+        &lists.basic_block_list,
+        &lists.address_independent_basic_block_list,
+    ] {
+        if cache.count(list)? > 0 {
+            return Err(application_error!(format!(
+                "{} is not empty, ingest the trace under another --trace-name",
+                list
+            )));
+        }
+    }
+
+    let trace_reader = trace::TraceReader::from_file(trace_file)?;
+
+    let progress_bar = ProgressBar::new(trace::total_basic_block_count(metadata_file)?);
+
+    // translated records refer to data already pushed to the raw basic block list
+    let mut raw_data_cache: LruCache<u64, Vec<u8>> = LruCache::new(16 * 1024);
+    let mut raw_basic_block_count = 0;
+    let mut address_independent_hashes = HashSet::new();
+
+    for (record_index, record) in trace_reader.enumerate() {
+        let trace::TraceRecord {
+            data,
+            program_counter,
+            execution_mode,
+            execution_privilege,
+            loop_count,
+        } = record?;
+
+        let data = match data {
+            trace::TraceBasicBlockData::Untranslated(data) => {
+                cache.push_basic_block_data(&lists.raw_basic_block_list, &data)?;
+                raw_basic_block_count += 1;
+                data
+            }
+
+            trace::TraceBasicBlockData::Translated(raw_index) => {
+                if raw_index >= raw_basic_block_count {
+                    return Err(Error::Record {
+                        index: record_index,
+                        error: RecordError::UnknownBackReference(raw_index),
+                    });
+                }
+
+                if let Some(data) = raw_data_cache.get(&raw_index) {
+                    data.clone()
+                } else {
+                    let data =
+                        cache.basic_block_data(&lists.raw_basic_block_list, raw_index as usize)?;
+                    raw_data_cache.put(raw_index, data.clone());
+                    data
+                }
+            }
+        };
+
+        let basic_block = cache::RawBasicBlock {
+            program_counter,
+            execution_mode,
+            execution_privilege,
+            loop_count,
+            data,
+        };
+
+        cache.push_basic_block_data(&lists.basic_block_list, &basic_block.serialize()?)?;
+
+        let address_independent_basic_block = basic_block.serialize_address_independent();
+        if address_independent_hashes.insert(metro::hash64(&address_independent_basic_block)) {
+            cache.push_basic_block_data(
+                &lists.address_independent_basic_block_list,
+                &address_independent_basic_block,
+            )?;
+        }
+
+        progress_bar.inc(1);
+    }
+
+    progress_bar.finish();
+
+    println!(
+        "raw basic blocks: {} (total: {})",
+        cache.count(&lists.raw_basic_block_list)?,
+        cache.count(&lists.basic_block_list)?
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::TryFrom,
+        env,
+        fs::{self, File},
+        io::Write,
+        path::{Path, PathBuf},
+        process,
+    };
+
+    use scroll::IOwrite;
+
+    use super::{ingest_trace, TraceLists};
+    use crate::{
+        args::{ExecutionMode, ExecutionPrivilege},
+        cache::{BasicBlock, Cache, RawBasicBlock},
+        error::{Error, RecordError},
+    };
+
+    // an empty directory for the store and the trace files of a test
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = env::temp_dir().join(format!("bbclient-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    // trace and metadata files as written by the tracer, a record refers to the raw basic block
+    // list (i.e. is translated) if it has a raw index
+    fn write_trace(
+        directory: &Path,
+        records: &[(&RawBasicBlock, Option<u64>)],
+    ) -> (PathBuf, PathBuf) {
+        let trace_file = directory.join("trace.lz4");
+        let mut encoder = lz4::EncoderBuilder::new()
+            .build(File::create(&trace_file).unwrap())
+            .unwrap();
+        for &(basic_block, raw_index) in records {
+            match raw_index {
+                Some(raw_index) => {
+                    encoder.iowrite(1u8).unwrap();
+                    encoder.iowrite(raw_index).unwrap();
+                }
+
+                None => {
+                    encoder.iowrite(0u8).unwrap();
+                    encoder.iowrite(basic_block.data.len() as u16).unwrap();
+                    encoder.write_all(&basic_block.data).unwrap();
+                }
+            }
+            encoder.iowrite(basic_block.program_counter).unwrap();
+            encoder.iowrite(basic_block.execution_mode).unwrap();
+            encoder.iowrite(basic_block.execution_privilege).unwrap();
+            encoder.iowrite(basic_block.loop_count).unwrap();
+        }
+        let (_, result) = encoder.finish();
+        result.unwrap();
+
+        let metadata_file = directory.join("trace.meta");
+        File::create(&metadata_file)
+            .unwrap()
+            .iowrite(records.len() as u64)
+            .unwrap();
+
+        (trace_file, metadata_file)
+    }
+
+    fn raw_basic_blocks() -> Vec<RawBasicBlock> {
+        vec![
+            RawBasicBlock {
+                program_counter: 0x0000_0000_0040_1000,
+                execution_mode: u8::from(ExecutionMode::Bit64),
+                execution_privilege: 3,
+                loop_count: 1,
+                data: vec![0x48, 0x31, 0xc0, 0xc3],
+            },
+            RawBasicBlock {
+                program_counter: 0xffff_f800_0000_1000,
+                execution_mode: u8::from(ExecutionMode::Compat),
+                execution_privilege: 0,
+                loop_count: 7,
+                data: vec![0x90, 0xc3],
+            },
+        ]
+    }
+
+    #[test]
+    fn ingested_basic_blocks_round_trip() {
+        let directory = test_directory("round-trip");
+        let raw_basic_blocks = raw_basic_blocks();
+        // the last record is translated, its data is the first raw basic block
+        let records = [
+            (&raw_basic_blocks[0], None),
+            (&raw_basic_blocks[1], None),
+            (&raw_basic_blocks[0], Some(0)),
+        ];
+        let (trace_file, metadata_file) = write_trace(&directory, &records);
+
+        let cache = Cache::from_directory(&directory.join("store")).unwrap();
+        let lists = TraceLists::from_trace_name(None);
+        ingest_trace(&cache, &lists, &trace_file, &metadata_file).unwrap();
+
+        assert_eq!(cache.count(&lists.raw_basic_block_list).unwrap(), 2);
+
+        let basic_blocks: Vec<_> = cache
+            .basic_blocks::<BasicBlock>(&lists.basic_block_list, 0)
+            .unwrap()
+            .map(|basic_block| basic_block.unwrap().1)
+            .collect();
+        assert_eq!(basic_blocks.len(), records.len());
+        for (basic_block, &(expected, _)) in basic_blocks.iter().zip(&records) {
+            assert_eq!(basic_block.program_counter, expected.program_counter);
+            assert_eq!(
+                basic_block.execution_mode,
+                ExecutionMode::try_from(expected.execution_mode).unwrap()
+            );
+            assert_eq!(
+                basic_block.execution_privilege,
+                ExecutionPrivilege::try_from(expected.execution_privilege).unwrap()
+            );
+            assert_eq!(basic_block.loop_count, expected.loop_count);
+            assert_eq!(basic_block.data, expected.data);
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn traces_are_not_ingested_into_non_empty_lists() {
+        let directory = test_directory("ingest-twice");
+        let raw_basic_blocks = raw_basic_blocks();
+        let records = [
+            (&raw_basic_blocks[0], None),
+            (&raw_basic_blocks[0], Some(0)),
+        ];
+        let (trace_file, metadata_file) = write_trace(&directory, &records);
+
+        let cache = Cache::from_directory(&directory.join("store")).unwrap();
+        let lists = TraceLists::from_trace_name(None);
+        ingest_trace(&cache, &lists, &trace_file, &metadata_file).unwrap();
+
+        match ingest_trace(&cache, &lists, &trace_file, &metadata_file) {
+            Err(Error::Application(_)) => (),
+
+            Err(err) => panic!("unexpected error: {}", err),
+
+            Ok(()) => panic!("a trace was ingested into non-empty lists"),
+        }
+        assert_eq!(cache.count(&lists.raw_basic_block_list).unwrap(), 1);
+        assert_eq!(cache.count(&lists.basic_block_list).unwrap(), 2);
+
+        // under another trace name, the lists are distinct
+        let named_lists = TraceLists::from_trace_name(Some("second"));
+        ingest_trace(&cache, &named_lists, &trace_file, &metadata_file).unwrap();
+        assert_eq!(cache.count(&named_lists.raw_basic_block_list).unwrap(), 1);
+        assert_eq!(cache.count(&named_lists.basic_block_list).unwrap(), 2);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn unknown_back_reference_is_a_record_error() {
+        let directory = test_directory("back-reference");
+        let raw_basic_blocks = raw_basic_blocks();
+        let records = [
+            (&raw_basic_blocks[0], None),
+            (&raw_basic_blocks[1], Some(1)),
+        ];
+        let (trace_file, metadata_file) = write_trace(&directory, &records);
+
+        let cache = Cache::from_directory(&directory.join("store")).unwrap();
+        let lists = TraceLists::from_trace_name(None);
+        match ingest_trace(&cache, &lists, &trace_file, &metadata_file) {
+            Err(Error::Record {
+                index: 1,
+                error: RecordError::UnknownBackReference(1),
+            }) => (),
+
+            Err(err) => panic!("unexpected error: {}", err),
+
+            Ok(()) => panic!("a basic block with unknown data was ingested"),
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
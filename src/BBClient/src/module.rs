@@ -0,0 +1,152 @@
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use crate::{args::parse_hex, error::Result};
+
+pub(crate) struct Module {
+    pub name: String,
+    pub base: u64,
+    pub size: u64,
+    pub path: Option<PathBuf>,
+}
+
+impl Module {
+    pub fn end(&self) -> u64 {
+        self.base.saturating_add(self.size)
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        self.base <= address && address < self.end()
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ModuleMap {
+    // sorted by base address
+    modules: Vec<Module>,
+}
+
+impl ModuleMap {
+    // each non-empty line which is not a comment (#) describes a module:
+    //   name base size [path]
+    // where the base address and the size are hexadecimal
+    // relative paths are relative to the directory of the map
+    pub fn from_file(module_map_file: &Path) -> Result<Self> {
+        let map_directory = module_map_file.parent().unwrap_or_else(|| Path::new(""));
+        let mut modules = vec![];
+
+        for line in BufReader::new(File::open(module_map_file)?).lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if fields.len() < 3 {
+                return Err(application_error!(format!(
+                    "bad module map entry: {}",
+                    line
+                )));
+            }
+
+            modules.push(Module {
+                name: fields[0].to_owned(),
+                base: parse_hex(fields[1])?,
+                size: parse_hex(fields[2])?,
+                path: fields.get(3).map(|path| map_directory.join(path)),
+            });
+        }
+
+        modules.sort_by_key(|module| module.base);
+
+        Ok(ModuleMap { modules })
+    }
+
+    // modules covering the given (sorted) basic blocks, split so that every block starts
+    // within 4 GiB of the base of its module
+    pub fn synthetic(name: &str, basic_blocks: &BTreeSet<(u64, u16)>) -> Self {
+        let mut modules: Vec<Module> = vec![];
+
+        for &(start, size) in basic_blocks {
+            let end = start.saturating_add(u64::from(size));
+            match modules.last_mut() {
+                Some(ref mut module) if start - module.base <= u64::from(u32::max_value()) => {
+                    module.size = module.size.max(end - module.base);
+                }
+
+                _ => {
+                    if let Some(module) = modules.last_mut() {
+                        module.size = module.size.min(start - module.base);
+                    }
+                    modules.push(Module {
+                        name: String::new(),
+                        base: start,
+                        size: end - start,
+                        path: None,
+                    });
+                }
+            }
+        }
+
+        let module_count = modules.len();
+        for (index, module) in modules.iter_mut().enumerate() {
+            module.name = if module_count == 1 {
+                name.to_owned()
+            } else {
+                format!("{}.{}", name, index)
+            };
+        }
+
+        ModuleMap { modules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+
+    pub fn modules(&self) -> &[Module] {
+        &self.modules
+    }
+
+    // index and module containing the address
+    pub fn find(&self, address: u64) -> Option<(usize, &Module)> {
+        let candidate = match self
+            .modules
+            .binary_search_by_key(&address, |module| module.base)
+        {
+            Ok(index) => index,
+
+            Err(0) => return None,
+
+            Err(index) => index - 1,
+        };
+
+        let module = &self.modules[candidate];
+        if module.contains(address) {
+            Some((candidate, module))
+        } else {
+            None
+        }
+    }
+
+    // module containing the address, and the offset of the address in the module
+    pub fn locate(&self, address: u64) -> Option<(&Module, u64)> {
+        self.find(address)
+            .map(|(_, module)| (module, address - module.base))
+    }
+
+    // ntdll+0x1c
+    pub fn tag(&self, address: u64) -> Option<String> {
+        self.locate(address)
+            .map(|(module, offset)| format!("{}+0x{:x}", module.name, offset))
+    }
+
+    pub fn contains_module(&self, name: &str) -> bool {
+        self.modules.iter().any(|module| module.name == name)
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::BTreeMap,
+    thread::{self, JoinHandle},
+};
+
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+
+use crate::{
+    args::{ExecutionMode, NamingScheme},
+    error::Result,
+    iname::{self, DecodedInstruction},
+};
+
+pub(crate) struct BasicBlockJob {
+    pub index: usize,
+    pub execution_mode: ExecutionMode,
+    pub data: Vec<u8>,
+}
+
+pub(crate) struct DecodedBasicBlock {
+    pub index: usize,
+    pub execution_mode: ExecutionMode,
+    pub instructions: Vec<DecodedInstruction>,
+}
+
+// batches of basic blocks are decoded by a pool of XED workers, then given back in the order
+// they have been submitted
+pub(crate) struct DecoderPool {
+    job_sender: Option<Sender<(usize, Vec<BasicBlockJob>)>>,
+    result_receiver: Receiver<(usize, Vec<DecodedBasicBlock>)>,
+    workers: Vec<JoinHandle<()>>,
+    // batches decoded ahead of the next one to give back
+    pending_results: BTreeMap<usize, Vec<DecodedBasicBlock>>,
+    submitted_batches: usize,
+    received_batches: usize,
+}
+
+impl DecoderPool {
+    pub fn new(jobs: usize, naming: NamingScheme) -> Self {
+        iname::initialize_xed();
+
+        let jobs = jobs.max(1);
+
+        // limits the batches read ahead of the workers
+        let (job_sender, job_receiver) = bounded::<(usize, Vec<BasicBlockJob>)>(2 * jobs);
+        let (result_sender, result_receiver) = unbounded();
+
+        let workers = (0..jobs)
+            .map(|_| {
+                let job_receiver = job_receiver.clone();
+                let result_sender = result_sender.clone();
+
+                thread::spawn(move || {
+                    for (batch_index, batch) in job_receiver.iter() {
+                        let decoded_batch = batch
+                            .into_iter()
+                            .map(|job| DecodedBasicBlock {
+                                index: job.index,
+                                execution_mode: job.execution_mode,
+                                instructions: iname::decode_basic_block(
+                                    &job.data,
+                                    job.execution_mode,
+                                    naming,
+                                ),
+                            })
+                            .collect();
+
+                        if result_sender.send((batch_index, decoded_batch)).is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        DecoderPool {
+            job_sender: Some(job_sender),
+            result_receiver,
+            workers,
+            pending_results: BTreeMap::new(),
+            submitted_batches: 0,
+            received_batches: 0,
+        }
+    }
+
+    // blocks while the workers are busy with the previous batches
+    pub fn submit(&mut self, batch: Vec<BasicBlockJob>) -> Result<()> {
+        let job_sender = self
+            .job_sender
+            .as_ref()
+            .ok_or_else(|| application_error!("decoding is finished"))?;
+
+        job_sender
+            .send((self.submitted_batches, batch))
+            .map_err(|_| application_error!("decoding workers stopped"))?;
+        self.submitted_batches += 1;
+
+        Ok(())
+    }
+
+    // no more batches will be submitted
+    fn finish(&mut self) {
+        self.job_sender = None;
+    }
+
+    // the next decoded batch if it is ready
+    pub fn try_receive(&mut self) -> Option<Vec<DecodedBasicBlock>> {
+        while let Ok((batch_index, decoded_batch)) = self.result_receiver.try_recv() {
+            self.pending_results.insert(batch_index, decoded_batch);
+        }
+
+        self.next_pending_result()
+    }
+
+    // once all batches are submitted: the next decoded batch, waiting for it if needed; none
+    // once all submitted batches are received
+    pub fn receive(&mut self) -> Result<Option<Vec<DecodedBasicBlock>>> {
+        self.finish();
+
+        while self.received_batches < self.submitted_batches {
+            if let Some(decoded_batch) = self.next_pending_result() {
+                return Ok(Some(decoded_batch));
+            }
+
+            let (batch_index, decoded_batch) = self
+                .result_receiver
+                .recv()
+                .map_err(|_| application_error!("decoding workers stopped"))?;
+            self.pending_results.insert(batch_index, decoded_batch);
+        }
+
+        Ok(None)
+    }
+
+    fn next_pending_result(&mut self) -> Option<Vec<DecodedBasicBlock>> {
+        let decoded_batch = self.pending_results.remove(&self.received_batches)?;
+        self.received_batches += 1;
+        Some(decoded_batch)
+    }
+}
+
+impl Drop for DecoderPool {
+    fn drop(&mut self) {
+        // workers stop once the submitted batches are decoded
+        self.finish();
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
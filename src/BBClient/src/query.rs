@@ -0,0 +1,487 @@
+use std::{iter::Peekable, str::Chars};
+
+use regex::Regex;
+use zydis::{
+    DecodedInstruction, DecodedOperand, OperandAction, OperandType, OperandVisibility, Register,
+};
+
+use crate::{
+    disasm::{DisasmBasicBlock, DisasmInst},
+    error::Result,
+};
+
+// Queries over the instructions of a basic block:
+//
+//   query       := and ('|' and)*
+//   and         := not ('&' not)*
+//   not         := '!' not | '(' query ')' | instruction
+//   instruction := '[' constraint (',' constraint)* ']' | constraint
+//   constraint  := MNEMONIC                 (same as mnemonic=MNEMONIC)
+//                | '/' REGEX '/'            (on the formatted instruction)
+//                | 'mnemonic' '=' MNEMONIC
+//                | 'reg' '=' REGISTER       (any register operand)
+//                | 'src' '=' REGISTER       (register operand read, maybe conditionally)
+//                | 'dst' '=' REGISTER       (register operand written, maybe conditionally)
+//                | 'mem'                    (any memory operand)
+//                | 'mem.base' '=' REGISTER | 'mem.index' '=' REGISTER
+//                | 'mem.seg' '=' REGISTER   | 'mem.disp' '=' NUMBER
+//                | KEY '.hidden' ('=' VALUE)?
+//                                           (same as the constraint KEY, other than mnemonic,
+//                                            hidden operands included: e.g. flags, the stack
+//                                            of push, call or ret)
+//
+// A block matches an instruction pattern if one of its instructions satisfies all of the
+// pattern constraints, e.g. `[mov, dst=cr3] & !/swapgs/`.
+
+#[derive(Debug)]
+enum Constraint {
+    Mnemonic(String),
+    Text(Regex),
+    // the name of the register, whether hidden operands are included
+    Register(String, bool),
+    SourceRegister(String, bool),
+    DestinationRegister(String, bool),
+    // the constraint on the memory operand if any, whether hidden operands are included
+    Memory(bool),
+    MemoryBase(String, bool),
+    MemoryIndex(String, bool),
+    MemorySegment(String, bool),
+    MemoryDisplacement(i64, bool),
+}
+
+// suffix of the constraint keys including hidden operands
+const HIDDEN_SUFFIX: &str = ".hidden";
+
+fn register_name(reg: Register) -> &'static str {
+    reg.get_string().unwrap_or("")
+}
+
+fn is_read(action: OperandAction) -> bool {
+    action.intersects(OperandAction::MASK_READ)
+}
+
+fn is_written(action: OperandAction) -> bool {
+    action.intersects(OperandAction::MASK_WRITE)
+}
+
+fn operands(ins: &DecodedInstruction) -> &[DecodedOperand] {
+    &ins.operands[..ins.operand_count as usize]
+}
+
+impl Constraint {
+    fn matches(&self, ins: &DisasmInst) -> bool {
+        let decoded = &ins.instruction;
+
+        let has_register =
+            |name: &str, hidden: bool, action_filter: &dyn Fn(OperandAction) -> bool| {
+                operands(decoded).iter().any(|op| {
+                    op.ty == OperandType::REGISTER
+                        && (hidden || op.visibility != OperandVisibility::HIDDEN)
+                        && action_filter(op.action)
+                        && register_name(op.reg) == name
+                })
+            };
+
+        let has_memory = |hidden: bool, filter: &dyn Fn(&DecodedOperand) -> bool| {
+            operands(decoded).iter().any(|op| {
+                op.ty == OperandType::MEMORY
+                    && (hidden || op.visibility != OperandVisibility::HIDDEN)
+                    && filter(op)
+            })
+        };
+
+        match self {
+            Constraint::Mnemonic(name) => decoded.mnemonic.get_string() == Some(name.as_str()),
+
+            Constraint::Text(regex) => regex.is_match(&ins.disasm),
+
+            Constraint::Register(name, hidden) => has_register(name.as_str(), *hidden, &|_| true),
+
+            Constraint::SourceRegister(name, hidden) => {
+                has_register(name.as_str(), *hidden, &is_read)
+            }
+
+            Constraint::DestinationRegister(name, hidden) => {
+                has_register(name.as_str(), *hidden, &is_written)
+            }
+
+            Constraint::Memory(hidden) => has_memory(*hidden, &|_| true),
+
+            Constraint::MemoryBase(name, hidden) => {
+                has_memory(*hidden, &|op| register_name(op.mem.base) == name.as_str())
+            }
+
+            Constraint::MemoryIndex(name, hidden) => {
+                has_memory(*hidden, &|op| register_name(op.mem.index) == name.as_str())
+            }
+
+            Constraint::MemorySegment(name, hidden) => has_memory(*hidden, &|op| {
+                register_name(op.mem.segment) == name.as_str()
+            }),
+
+            Constraint::MemoryDisplacement(disp, hidden) => {
+                has_memory(*hidden, &|op| op.mem.disp.value == *disp)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct InstructionPattern {
+    constraints: Vec<Constraint>,
+}
+
+impl InstructionPattern {
+    pub fn matches(&self, ins: &DisasmInst) -> bool {
+        self.constraints.iter().all(|c| c.matches(ins))
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Query {
+    Instruction(InstructionPattern),
+    Not(Box<Query>),
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Result<Self> {
+        let mut parser = Parser {
+            chars: query.chars().peekable(),
+        };
+
+        let parsed_query = parser.parse_query()?;
+        parser.skip_whitespaces();
+        if let Some(c) = parser.chars.next() {
+            return Err(application_error!(format!("unexpected '{}' in query", c)));
+        }
+
+        Ok(parsed_query)
+    }
+
+    pub fn matches(&self, basic_block: &DisasmBasicBlock) -> bool {
+        match self {
+            Query::Instruction(pattern) => basic_block
+                .instructions()
+                .iter()
+                .any(|ins| pattern.matches(ins)),
+
+            Query::Not(query) => !query.matches(basic_block),
+
+            Query::And(lhs, rhs) => lhs.matches(basic_block) && rhs.matches(basic_block),
+
+            Query::Or(lhs, rhs) => lhs.matches(basic_block) || rhs.matches(basic_block),
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespaces(&mut self) {
+        while self.chars.peek().map_or(false, |c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespaces();
+        self.chars.peek().cloned()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.chars.next();
+                Ok(())
+            }
+
+            _ => Err(application_error!(format!(
+                "expected '{}' in query",
+                expected
+            ))),
+        }
+    }
+
+    fn parse_query(&mut self) -> Result<Query> {
+        let mut query = self.parse_and()?;
+        while self.peek() == Some('|') {
+            self.chars.next();
+            query = Query::Or(Box::new(query), Box::new(self.parse_and()?));
+        }
+
+        Ok(query)
+    }
+
+    fn parse_and(&mut self) -> Result<Query> {
+        let mut query = self.parse_not()?;
+        while self.peek() == Some('&') {
+            self.chars.next();
+            query = Query::And(Box::new(query), Box::new(self.parse_not()?));
+        }
+
+        Ok(query)
+    }
+
+    fn parse_not(&mut self) -> Result<Query> {
+        match self.peek() {
+            Some('!') => {
+                self.chars.next();
+                Ok(Query::Not(Box::new(self.parse_not()?)))
+            }
+
+            Some('(') => {
+                self.chars.next();
+                let query = self.parse_query()?;
+                self.expect(')')?;
+                Ok(query)
+            }
+
+            Some('[') => {
+                self.chars.next();
+                let mut constraints = vec![self.parse_constraint()?];
+                while self.peek() == Some(',') {
+                    self.chars.next();
+                    constraints.push(self.parse_constraint()?);
+                }
+                self.expect(']')?;
+                Ok(Query::Instruction(InstructionPattern { constraints }))
+            }
+
+            _ => Ok(Query::Instruction(InstructionPattern {
+                constraints: vec![self.parse_constraint()?],
+            })),
+        }
+    }
+
+    fn parse_word(&mut self) -> Result<String> {
+        self.skip_whitespaces();
+
+        let mut word = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_alphanumeric() || c == '_' || c == '.' || c == '-' || c == '+' {
+                word.push(c);
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+
+        if word.is_empty() {
+            Err(application_error!("expected a word in query"))
+        } else {
+            Ok(word.to_lowercase())
+        }
+    }
+
+    fn parse_regex(&mut self) -> Result<Regex> {
+        let mut regex = String::new();
+        loop {
+            match self.chars.next() {
+                Some('\\') if self.chars.peek() == Some(&'/') => {
+                    self.chars.next();
+                    regex.push('/');
+                }
+
+                Some('/') => break,
+
+                Some(c) => regex.push(c),
+
+                None => return Err(application_error!("unterminated regex in query")),
+            }
+        }
+
+        Regex::new(&regex).map_err(|err| application_error!(format!("bad regex: {}", err)))
+    }
+
+    fn parse_constraint(&mut self) -> Result<Constraint> {
+        if self.peek() == Some('/') {
+            self.chars.next();
+            return Ok(Constraint::Text(self.parse_regex()?));
+        }
+
+        let key = self.parse_word()?;
+        if self.peek() != Some('=') {
+            return match key.as_str() {
+                "mem" => Ok(Constraint::Memory(false)),
+
+                "mem.hidden" => Ok(Constraint::Memory(true)),
+
+                _ => Ok(Constraint::Mnemonic(key)),
+            };
+        }
+
+        self.chars.next();
+        let value = self.parse_word()?;
+
+        let (key, hidden) = if key.ends_with(HIDDEN_SUFFIX) {
+            (&key[..key.len() - HIDDEN_SUFFIX.len()], true)
+        } else {
+            (key.as_str(), false)
+        };
+
+        match key {
+            "mnemonic" if !hidden => Ok(Constraint::Mnemonic(value)),
+
+            "reg" => Ok(Constraint::Register(value, hidden)),
+
+            "src" => Ok(Constraint::SourceRegister(value, hidden)),
+
+            "dst" => Ok(Constraint::DestinationRegister(value, hidden)),
+
+            "mem.base" => Ok(Constraint::MemoryBase(value, hidden)),
+
+            "mem.index" => Ok(Constraint::MemoryIndex(value, hidden)),
+
+            "mem.seg" => Ok(Constraint::MemorySegment(value, hidden)),
+
+            "mem.disp" => {
+                let (negative, value) = if value.starts_with('-') {
+                    (true, &value[1..])
+                } else {
+                    (false, value.as_str())
+                };
+
+                let disp = if value.starts_with("0x") {
+                    i64::from_str_radix(&value[2..], 16)
+                } else {
+                    value.parse::<i64>()
+                }
+                .map_err(|_| application_error!(format!("bad displacement: {}", value)))?;
+
+                Ok(Constraint::MemoryDisplacement(
+                    if negative { -disp } else { disp },
+                    hidden,
+                ))
+            }
+
+            _ => Err(application_error!(format!(
+                "unknown constraint '{}{}' in query",
+                key,
+                if hidden { HIDDEN_SUFFIX } else { "" }
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Constraint, Query};
+
+    fn render_constraint(constraint: &Constraint) -> String {
+        match constraint {
+            Constraint::Mnemonic(name) => name.clone(),
+            Constraint::Text(regex) => format!("/{}/", regex.as_str()),
+            Constraint::Register(name, hidden) => format!("reg{}={}", hidden_suffix(*hidden), name),
+            Constraint::SourceRegister(name, hidden) => {
+                format!("src{}={}", hidden_suffix(*hidden), name)
+            }
+            Constraint::DestinationRegister(name, hidden) => {
+                format!("dst{}={}", hidden_suffix(*hidden), name)
+            }
+            Constraint::Memory(hidden) => format!("mem{}", hidden_suffix(*hidden)),
+            Constraint::MemoryBase(name, hidden) => {
+                format!("mem.base{}={}", hidden_suffix(*hidden), name)
+            }
+            Constraint::MemoryIndex(name, hidden) => {
+                format!("mem.index{}={}", hidden_suffix(*hidden), name)
+            }
+            Constraint::MemorySegment(name, hidden) => {
+                format!("mem.seg{}={}", hidden_suffix(*hidden), name)
+            }
+            Constraint::MemoryDisplacement(disp, hidden) => {
+                format!("mem.disp{}={}", hidden_suffix(*hidden), disp)
+            }
+        }
+    }
+
+    fn hidden_suffix(hidden: bool) -> &'static str {
+        if hidden {
+            ".hidden"
+        } else {
+            ""
+        }
+    }
+
+    // fully parenthesized form of a parsed query
+    fn render(query: &str) -> String {
+        fn render_query(query: &Query) -> String {
+            match query {
+                Query::Instruction(pattern) => {
+                    let constraints: Vec<_> =
+                        pattern.constraints.iter().map(render_constraint).collect();
+                    format!("[{}]", constraints.join(","))
+                }
+                Query::Not(query) => format!("!{}", render_query(query)),
+                Query::And(lhs, rhs) => format!("({} & {})", render_query(lhs), render_query(rhs)),
+                Query::Or(lhs, rhs) => format!("({} | {})", render_query(lhs), render_query(rhs)),
+            }
+        }
+
+        render_query(&Query::parse(query).unwrap())
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(render("a | b & c"), "([a] | ([b] & [c]))");
+        assert_eq!(render("a & b | c"), "(([a] & [b]) | [c])");
+        assert_eq!(render("a & b & c"), "(([a] & [b]) & [c])");
+        assert_eq!(render("(a | b) & c"), "(([a] | [b]) & [c])");
+    }
+
+    #[test]
+    fn negation_binds_tightest() {
+        assert_eq!(render("!a & b"), "(![a] & [b])");
+        assert_eq!(render("!!a"), "!![a]");
+        assert_eq!(render("!(a | b)"), "!([a] | [b])");
+        assert_eq!(render("!/swapgs/"), "![/swapgs/]");
+    }
+
+    #[test]
+    fn groups_constrain_one_instruction() {
+        assert_eq!(
+            render("[mov, dst=CR3] & !/swapgs/"),
+            "([mov,dst=cr3] & ![/swapgs/])"
+        );
+        assert_eq!(
+            render("[mem, mem.base=rsp, mem.disp=-0x10]"),
+            "[mem,mem.base=rsp,mem.disp=-16]"
+        );
+        assert_eq!(
+            render("[src.hidden=rsp] | reg=eax"),
+            "([src.hidden=rsp] | [reg=eax])"
+        );
+        assert_eq!(
+            render("[mem.hidden, mem.base.hidden=RSP, mem.disp.hidden=8]"),
+            "[mem.hidden,mem.base.hidden=rsp,mem.disp.hidden=8]"
+        );
+        assert_eq!(render(r"/a\/b/"), "[/a/b/]");
+    }
+
+    #[test]
+    fn malformed_queries_are_rejected() {
+        for query in &[
+            "",
+            "a &",
+            "| a",
+            "(a",
+            "a)",
+            "[mov",
+            "[mov,]",
+            "[]",
+            "a b",
+            "/unterminated",
+            "/(/",
+            "foo=bar",
+            "mem.disp=zz",
+            "reg=",
+            "mnemonic.hidden=mov",
+            "foo.hidden=bar",
+        ] {
+            assert!(Query::parse(query).is_err(), "{:?} is parsed", query);
+        }
+    }
+}
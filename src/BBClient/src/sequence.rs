@@ -0,0 +1,112 @@
+use std::collections::VecDeque;
+
+use crate::{cache::BasicBlock, disasm::DisasmBasicBlock, error::Result, query::Query};
+
+struct WindowEntry {
+    index: usize,
+    basic_block: BasicBlock,
+    // which patterns the basic block matches
+    pattern_matches: Vec<bool>,
+}
+
+pub(crate) struct SequenceMatch {
+    // consecutive basic blocks of the window, from the first matched one to the last
+    pub basic_blocks: Vec<(usize, BasicBlock)>,
+    // indices of the basic blocks matching the patterns, in order
+    pub matched_indices: Vec<usize>,
+}
+
+// matches ordered patterns against distinct basic blocks spanning at most `window_size`
+// consecutive basic blocks of the trace
+pub(crate) struct SequenceMatcher {
+    patterns: Vec<Query>,
+    window_size: usize,
+    window: VecDeque<WindowEntry>,
+}
+
+impl SequenceMatcher {
+    pub fn new(patterns: Vec<Query>, window_size: usize) -> Result<Self> {
+        if patterns.is_empty() {
+            return Err(application_error!("no pattern given"));
+        }
+
+        if window_size < patterns.len() {
+            return Err(application_error!(
+                "the window must be at least as large as the number of patterns"
+            ));
+        }
+
+        Ok(SequenceMatcher {
+            patterns,
+            window_size,
+            window: VecDeque::with_capacity(window_size),
+        })
+    }
+
+    pub fn match_patterns(&self, disasm_basic_block: &DisasmBasicBlock) -> Vec<bool> {
+        self.patterns
+            .iter()
+            .map(|pattern| pattern.matches(disasm_basic_block))
+            .collect()
+    }
+
+    // basic blocks must be pushed in trace order, a match ending at the pushed basic block
+    // is returned and the window is restarted after it
+    pub fn push(
+        &mut self,
+        index: usize,
+        basic_block: BasicBlock,
+        pattern_matches: Vec<bool>,
+    ) -> Option<SequenceMatch> {
+        if self.window.len() == self.window_size {
+            self.window.pop_front();
+        }
+
+        let last_pattern = self.patterns.len() - 1;
+        let ends_match = pattern_matches[last_pattern];
+
+        self.window.push_back(WindowEntry {
+            index,
+            basic_block,
+            pattern_matches,
+        });
+
+        if !ends_match {
+            return None;
+        }
+
+        // earliest ordered match of the other patterns before the pushed basic block
+        let mut matched_positions = vec![];
+        for (position, entry) in self.window.iter().enumerate().take(self.window.len() - 1) {
+            if matched_positions.len() == last_pattern {
+                break;
+            }
+
+            if entry.pattern_matches[matched_positions.len()] {
+                matched_positions.push(position);
+            }
+        }
+
+        if matched_positions.len() < last_pattern {
+            return None;
+        }
+        matched_positions.push(self.window.len() - 1);
+
+        let first_position = matched_positions[0];
+        let matched_indices = matched_positions
+            .iter()
+            .map(|&position| self.window[position].index)
+            .collect();
+        let basic_blocks = self
+            .window
+            .drain(..)
+            .skip(first_position)
+            .map(|entry| (entry.index, entry.basic_block))
+            .collect();
+
+        Some(SequenceMatch {
+            basic_blocks,
+            matched_indices,
+        })
+    }
+}
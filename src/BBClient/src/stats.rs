@@ -0,0 +1,238 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+};
+
+use serde_derive::Serialize;
+use strum::AsStaticRef;
+use tabwriter::TabWriter;
+
+use crate::{
+    args::ExecutionMode, cache::BasicBlock, error::Result, module::ModuleMap, symbol::SymbolMap,
+};
+
+#[derive(Serialize)]
+pub(crate) struct BasicBlockStatistics {
+    pub program_counter: u64,
+    pub execution_mode: &'static str,
+    pub size: usize,
+    pub occurrences: u64,
+    pub executions: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+pub(crate) struct ExecutionCount {
+    pub basic_blocks: u64,
+    pub executions: u64,
+}
+
+impl ExecutionCount {
+    fn add(&mut self, executions: u64) {
+        self.basic_blocks += 1;
+        self.executions += executions;
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct TraceStatistics {
+    pub total_basic_blocks: u64,
+    pub unique_basic_blocks: usize,
+    pub total_executions: u64,
+    pub raw_basic_blocks: usize,
+    pub address_independent_basic_blocks: usize,
+    pub raw_to_address_independent_ratio: f64,
+    pub execution_modes: BTreeMap<&'static str, ExecutionCount>,
+    pub execution_privileges: BTreeMap<&'static str, ExecutionCount>,
+    // only with a module map
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub modules: BTreeMap<String, ExecutionCount>,
+    pub hottest_basic_blocks: Vec<BasicBlockStatistics>,
+}
+
+pub(crate) struct StatisticsCollector<'a> {
+    module_map: &'a ModuleMap,
+    basic_blocks: HashMap<(u64, ExecutionMode), BasicBlockStatistics>,
+    total_basic_blocks: u64,
+    total_executions: u64,
+    execution_modes: BTreeMap<&'static str, ExecutionCount>,
+    execution_privileges: BTreeMap<&'static str, ExecutionCount>,
+    modules: BTreeMap<String, ExecutionCount>,
+}
+
+// basic blocks outside of the modules of the map
+const UNKNOWN_MODULE: &str = "(unknown)";
+
+impl<'a> StatisticsCollector<'a> {
+    pub fn new(module_map: &'a ModuleMap) -> Self {
+        StatisticsCollector {
+            module_map,
+            basic_blocks: HashMap::new(),
+            total_basic_blocks: 0,
+            total_executions: 0,
+            execution_modes: BTreeMap::new(),
+            execution_privileges: BTreeMap::new(),
+            modules: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_basic_block(&mut self, basic_block: &BasicBlock) {
+        let executions = basic_block.executions();
+
+        let basic_block_statistics = self
+            .basic_blocks
+            .entry((basic_block.program_counter, basic_block.execution_mode))
+            .or_insert_with(|| BasicBlockStatistics {
+                program_counter: basic_block.program_counter,
+                execution_mode: basic_block.execution_mode.as_static(),
+                size: basic_block.data.len(),
+                occurrences: 0,
+                executions: 0,
+                symbol: None,
+            });
+        basic_block_statistics.occurrences += 1;
+        basic_block_statistics.executions += executions;
+
+        self.total_basic_blocks += 1;
+        self.total_executions += executions;
+
+        self.execution_modes
+            .entry(basic_block.execution_mode.as_static())
+            .or_insert_with(ExecutionCount::default)
+            .add(executions);
+        self.execution_privileges
+            .entry(basic_block.execution_privilege.as_static())
+            .or_insert_with(ExecutionCount::default)
+            .add(executions);
+
+        if !self.module_map.is_empty() {
+            let module_name = self
+                .module_map
+                .locate(basic_block.program_counter)
+                .map_or(UNKNOWN_MODULE, |(module, _)| module.name.as_str());
+            self.modules
+                .entry(module_name.to_owned())
+                .or_insert_with(ExecutionCount::default)
+                .add(executions);
+        }
+    }
+
+    pub fn finish(
+        self,
+        top: usize,
+        raw_basic_blocks: usize,
+        address_independent_basic_blocks: usize,
+    ) -> TraceStatistics {
+        let unique_basic_blocks = self.basic_blocks.len();
+
+        let mut hottest_basic_blocks: Vec<_> =
+            self.basic_blocks.into_iter().map(|(_, bb)| bb).collect();
+        hottest_basic_blocks.sort_by(|bb0, bb1| {
+            bb1.executions
+                .cmp(&bb0.executions)
+                .then(bb0.program_counter.cmp(&bb1.program_counter))
+        });
+        hottest_basic_blocks.truncate(top);
+
+        let raw_to_address_independent_ratio = if address_independent_basic_blocks == 0 {
+            0f64
+        } else {
+            raw_basic_blocks as f64 / address_independent_basic_blocks as f64
+        };
+
+        TraceStatistics {
+            total_basic_blocks: self.total_basic_blocks,
+            unique_basic_blocks,
+            total_executions: self.total_executions,
+            raw_basic_blocks,
+            address_independent_basic_blocks,
+            raw_to_address_independent_ratio,
+            execution_modes: self.execution_modes,
+            execution_privileges: self.execution_privileges,
+            modules: self.modules,
+            hottest_basic_blocks,
+        }
+    }
+}
+
+impl TraceStatistics {
+    fn write_execution_counts<W: Write, K: AsRef<str>>(
+        tw: &mut TabWriter<W>,
+        title: &str,
+        execution_counts: &BTreeMap<K, ExecutionCount>,
+    ) -> Result<()> {
+        writeln!(tw, "{}\tbasic blocks\texecutions", title)?;
+        for (name, execution_count) in execution_counts {
+            writeln!(
+                tw,
+                "{}\t{}\t{}",
+                name.as_ref(),
+                execution_count.basic_blocks,
+                execution_count.executions
+            )?;
+        }
+        writeln!(tw)?;
+
+        Ok(())
+    }
+
+    pub fn symbolize(&mut self, symbols: &SymbolMap) {
+        for basic_block in &mut self.hottest_basic_blocks {
+            basic_block.symbol = symbols.symbolize(basic_block.program_counter);
+        }
+    }
+
+    pub fn write_table<W: Write>(&self, output: &mut W) -> Result<()> {
+        let mut tw = TabWriter::new(output).padding(4);
+
+        writeln!(tw, "total basic blocks\t{}", self.total_basic_blocks)?;
+        writeln!(tw, "unique basic blocks\t{}", self.unique_basic_blocks)?;
+        writeln!(tw, "total executions\t{}", self.total_executions)?;
+        writeln!(tw, "raw basic blocks\t{}", self.raw_basic_blocks)?;
+        writeln!(
+            tw,
+            "address independent basic blocks\t{}",
+            self.address_independent_basic_blocks
+        )?;
+        writeln!(
+            tw,
+            "raw / address independent\t{:.3}",
+            self.raw_to_address_independent_ratio
+        )?;
+        writeln!(tw)?;
+
+        TraceStatistics::write_execution_counts(&mut tw, "mode", &self.execution_modes)?;
+        TraceStatistics::write_execution_counts(&mut tw, "privilege", &self.execution_privileges)?;
+        if !self.modules.is_empty() {
+            TraceStatistics::write_execution_counts(&mut tw, "module", &self.modules)?;
+        }
+
+        writeln!(
+            tw,
+            "program counter\tsymbol\tmode\tsize\toccurrences\texecutions"
+        )?;
+        for basic_block in &self.hottest_basic_blocks {
+            writeln!(
+                tw,
+                "0x{:016x}\t{}\t{}\t{}\t{}\t{}",
+                basic_block.program_counter,
+                basic_block.symbol.as_ref().map_or("-", String::as_str),
+                basic_block.execution_mode,
+                basic_block.size,
+                basic_block.occurrences,
+                basic_block.executions
+            )?;
+        }
+        tw.flush()?;
+
+        Ok(())
+    }
+
+    pub fn write_json<W: Write>(&self, output: &mut W) -> Result<()> {
+        serde_json::to_writer_pretty(&mut *output, self)?;
+        writeln!(output)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,411 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use redis::{cmd, Client, Commands, Connection, IntoConnectionInfo};
+use scroll::{IOread, IOwrite};
+
+use crate::error::Result;
+
+// lists of binary records, as they are kept by the cache, plus sets and single values
+// which keep the state of the commands across runs
+pub(crate) trait Storage {
+    fn list_exists(&self, list: &str) -> Result<bool>;
+
+    fn list_length(&self, list: &str) -> Result<usize>;
+
+    fn list_index(&self, list: &str, index: usize) -> Result<Vec<u8>>;
+
+    // at most `count` records, starting from the index `start`
+    fn list_range(&self, list: &str, start: usize, count: usize) -> Result<Vec<Vec<u8>>>;
+
+    fn list_push(&self, list: &str, data: &[u8]) -> Result<()>;
+
+    fn lists(&self) -> Result<Vec<String>>;
+
+    fn set_members(&self, set: &str) -> Result<Vec<Vec<u8>>>;
+
+    fn set_add(&self, set: &str, member: &[u8]) -> Result<()>;
+
+    fn value_get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    fn value_set(&self, key: &str, value: &[u8]) -> Result<()>;
+}
+
+pub(crate) struct RedisStorage {
+    connection: Connection,
+}
+
+impl RedisStorage {
+    pub fn from_url(redis_server_url: &str, database: Option<i64>) -> Result<Self> {
+        let mut connection_info = redis_server_url.into_connection_info()?;
+        if let Some(database) = database {
+            connection_info.db = database;
+        }
+
+        let client = Client::open(connection_info)?;
+        let connection = client.get_connection()?;
+
+        Ok(RedisStorage { connection })
+    }
+}
+
+impl Storage for RedisStorage {
+    fn list_exists(&self, list: &str) -> Result<bool> {
+        let list_exists: bool = self.connection.exists(list)?;
+        if list_exists {
+            let cached_type_name: String = cmd("TYPE").arg(list).query(&self.connection)?;
+
+            if cached_type_name == "list" {
+                Ok(true)
+            } else {
+                Err(application_error!(format!("cached {} is not a list", list)))
+            }
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn list_length(&self, list: &str) -> Result<usize> {
+        self.connection.llen(list).map_err(From::from)
+    }
+
+    fn list_index(&self, list: &str, index: usize) -> Result<Vec<u8>> {
+        self.connection
+            .lindex(list, index as isize)
+            .map_err(From::from)
+    }
+
+    fn list_range(&self, list: &str, start: usize, count: usize) -> Result<Vec<Vec<u8>>> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+
+        self.connection
+            .lrange(list, start as isize, (start + count - 1) as isize)
+            .map_err(From::from)
+    }
+
+    fn list_push(&self, list: &str, data: &[u8]) -> Result<()> {
+        let _: () = self.connection.rpush(list, data)?;
+        Ok(())
+    }
+
+    fn lists(&self) -> Result<Vec<String>> {
+        let keys: Vec<String> = self.connection.scan()?.collect();
+
+        let mut lists = vec![];
+        for key in keys {
+            let cached_type_name: String = cmd("TYPE").arg(&key).query(&self.connection)?;
+            if cached_type_name == "list" {
+                lists.push(key);
+            }
+        }
+
+        Ok(lists)
+    }
+
+    fn set_members(&self, set: &str) -> Result<Vec<Vec<u8>>> {
+        self.connection.smembers(set).map_err(From::from)
+    }
+
+    fn set_add(&self, set: &str, member: &[u8]) -> Result<()> {
+        let _: () = self.connection.sadd(set, member)?;
+        Ok(())
+    }
+
+    fn value_get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.connection.get(key).map_err(From::from)
+    }
+
+    fn value_set(&self, key: &str, value: &[u8]) -> Result<()> {
+        let _: () = self.connection.set(key, value)?;
+        Ok(())
+    }
+}
+
+// each list is an append-only segment file holding the concatenated records, plus an
+// index file holding the end offset (u64) of each record in the segment; a namespaced
+// list `trace:list` is kept in the sub-directory `trace`
+struct Segment {
+    data: File,
+    index: File,
+}
+
+impl Segment {
+    fn open(data_path: &Path, index_path: &Path) -> Result<Self> {
+        if let Some(parent) = data_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        Segment::truncate_unindexed(data_path, index_path)?;
+
+        let open = |path: &Path| {
+            OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(path)
+        };
+
+        Ok(Segment {
+            data: open(data_path)?,
+            index: open(index_path)?,
+        })
+    }
+
+    // a record is written, then indexed: the data following the last indexed record, and a
+    // partial index entry, are left by an interrupted push and are dropped (files opened for
+    // appending cannot be truncated on every platform, hence the separate handles)
+    fn truncate_unindexed(data_path: &Path, index_path: &Path) -> Result<()> {
+        let indexed_end_offset = if index_path.is_file() {
+            let mut index = OpenOptions::new().read(true).write(true).open(index_path)?;
+            let length = index.metadata()?.len() / INDEX_ENTRY_SIZE;
+            index.set_len(length * INDEX_ENTRY_SIZE)?;
+
+            if length == 0 {
+                0
+            } else {
+                index.seek(SeekFrom::Start((length - 1) * INDEX_ENTRY_SIZE))?;
+                index.ioread::<u64>()?
+            }
+        } else {
+            0
+        };
+
+        if data_path.is_file() {
+            let data = OpenOptions::new().write(true).open(data_path)?;
+            if data.metadata()?.len() > indexed_end_offset {
+                data.set_len(indexed_end_offset)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn length(&self) -> Result<usize> {
+        Ok((self.index.metadata()?.len() / INDEX_ENTRY_SIZE) as usize)
+    }
+
+    fn end_offset(&mut self, index: usize) -> Result<u64> {
+        self.index
+            .seek(SeekFrom::Start(index as u64 * INDEX_ENTRY_SIZE))?;
+        self.index.ioread::<u64>().map_err(From::from)
+    }
+
+    fn get(&mut self, index: usize) -> Result<Vec<u8>> {
+        if index >= self.length()? {
+            return Err(application_error!("list index out of range"));
+        }
+
+        let begin_offset = if index == 0 {
+            0
+        } else {
+            self.end_offset(index - 1)?
+        };
+        let end_offset = self.end_offset(index)?;
+
+        let mut data = vec![0u8; (end_offset - begin_offset) as usize];
+        self.data.seek(SeekFrom::Start(begin_offset))?;
+        self.data.read_exact(&mut data)?;
+
+        Ok(data)
+    }
+
+    fn push(&mut self, data: &[u8]) -> Result<()> {
+        self.data.write_all(data)?;
+        let end_offset = self.data.metadata()?.len();
+        self.index.iowrite(end_offset)?;
+
+        Ok(())
+    }
+}
+
+const SEGMENT_EXTENSION: &str = "seg";
+const INDEX_EXTENSION: &str = "idx";
+const INDEX_ENTRY_SIZE: u64 = 8;
+const NAMESPACE_SEPARATOR: char = ':';
+// a set is a file of its members, each one prefixed by its length (u32), in insertion order
+const SET_EXTENSION: &str = "set";
+// a value is a file of its content, replaced as a whole
+const VALUE_EXTENSION: &str = "val";
+const TEMPORARY_EXTENSION: &str = "tmp";
+
+pub(crate) struct FileStorage {
+    directory: PathBuf,
+    segments: RefCell<HashMap<String, Segment>>,
+}
+
+impl FileStorage {
+    pub fn from_directory(directory: &Path) -> Result<Self> {
+        fs::create_dir_all(directory)?;
+
+        Ok(FileStorage {
+            directory: directory.to_owned(),
+            segments: RefCell::new(HashMap::new()),
+        })
+    }
+
+    // the extension is appended, so that a dot in the name (e.g. `ls.v2`) is kept
+    fn key_path(&self, key: &str, extension: &str) -> PathBuf {
+        let mut path = key
+            .split(NAMESPACE_SEPARATOR)
+            .fold(self.directory.clone(), |path, component| {
+                path.join(component)
+            })
+            .into_os_string();
+        path.push(".");
+        path.push(extension);
+
+        PathBuf::from(path)
+    }
+
+    fn collect_lists(directory: &Path, namespace: &str, lists: &mut Vec<String>) -> Result<()> {
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+
+            // a namespace is a whole directory name, a list is an index file name without
+            // its extension
+            let is_dir = path.is_dir();
+            let name = if is_dir {
+                path.file_name()
+            } else if path.extension().and_then(|ext| ext.to_str()) == Some(INDEX_EXTENSION) {
+                path.file_stem()
+            } else {
+                continue;
+            };
+
+            let name = match name.and_then(|name| name.to_str()) {
+                Some(name) if namespace.is_empty() => name.to_owned(),
+                Some(name) => format!("{}{}{}", namespace, NAMESPACE_SEPARATOR, name),
+                None => continue,
+            };
+
+            if is_dir {
+                FileStorage::collect_lists(&path, &name, lists)?;
+            } else {
+                lists.push(name);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn with_segment<T, F>(&self, list: &str, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Segment) -> Result<T>,
+    {
+        let mut segments = self.segments.borrow_mut();
+        if !segments.contains_key(list) {
+            let segment = Segment::open(
+                &self.key_path(list, SEGMENT_EXTENSION),
+                &self.key_path(list, INDEX_EXTENSION),
+            )?;
+            segments.insert(list.to_owned(), segment);
+        }
+
+        f(segments.get_mut(list).unwrap())
+    }
+}
+
+impl Storage for FileStorage {
+    fn list_exists(&self, list: &str) -> Result<bool> {
+        Ok(self.segments.borrow().contains_key(list)
+            || self.key_path(list, INDEX_EXTENSION).is_file())
+    }
+
+    fn list_length(&self, list: &str) -> Result<usize> {
+        if self.list_exists(list)? {
+            self.with_segment(list, |segment| segment.length())
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn list_index(&self, list: &str, index: usize) -> Result<Vec<u8>> {
+        self.with_segment(list, |segment| segment.get(index))
+    }
+
+    fn list_range(&self, list: &str, start: usize, count: usize) -> Result<Vec<Vec<u8>>> {
+        self.with_segment(list, |segment| {
+            let end = segment.length()?.min(start + count);
+            (start..end).map(|index| segment.get(index)).collect()
+        })
+    }
+
+    fn list_push(&self, list: &str, data: &[u8]) -> Result<()> {
+        self.with_segment(list, |segment| segment.push(data))
+    }
+
+    fn lists(&self) -> Result<Vec<String>> {
+        let mut lists = vec![];
+        FileStorage::collect_lists(&self.directory, "", &mut lists)?;
+
+        Ok(lists)
+    }
+
+    fn set_members(&self, set: &str) -> Result<Vec<Vec<u8>>> {
+        let set_path = self.key_path(set, SET_EXTENSION);
+        if !set_path.is_file() {
+            return Ok(vec![]);
+        }
+
+        let mut set_file = BufReader::new(File::open(set_path)?);
+        let mut members = HashSet::new();
+        // a member partially written by an interrupted run is dropped
+        while let Ok(length) = set_file.ioread::<u32>() {
+            let mut member = vec![0u8; length as usize];
+            if set_file.read_exact(&mut member).is_err() {
+                break;
+            }
+            members.insert(member);
+        }
+
+        Ok(members.into_iter().collect())
+    }
+
+    fn set_add(&self, set: &str, member: &[u8]) -> Result<()> {
+        let set_path = self.key_path(set, SET_EXTENSION);
+        if let Some(parent) = set_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut set_file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(set_path)?;
+        let mut record = Vec::with_capacity(4 + member.len());
+        record.iowrite(member.len() as u32)?;
+        record.extend_from_slice(member);
+        set_file.write_all(&record)?;
+
+        Ok(())
+    }
+
+    fn value_get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let value_path = self.key_path(key, VALUE_EXTENSION);
+        if value_path.is_file() {
+            Ok(Some(fs::read(value_path)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn value_set(&self, key: &str, value: &[u8]) -> Result<()> {
+        let value_path = self.key_path(key, VALUE_EXTENSION);
+        if let Some(parent) = value_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // the previous value is kept if the run is interrupted while writing
+        let temporary_path = value_path.with_extension(TEMPORARY_EXTENSION);
+        fs::write(&temporary_path, value)?;
+        fs::rename(temporary_path, value_path)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, Read},
+    path::PathBuf,
+};
+
+use goblin::elf::{sym, Elf};
+
+use crate::{args::parse_hex, error::Result};
+
+// a symbol file, whose symbol addresses are moved by the load base
+#[derive(Debug)]
+pub(crate) struct SymbolSource {
+    pub path: PathBuf,
+    pub base: u64,
+}
+
+impl SymbolSource {
+    // path[@base], where the base is hexadecimal
+    pub fn parse(source: &str) -> Result<Self> {
+        match source.rfind('@') {
+            Some(separator) => Ok(SymbolSource {
+                path: PathBuf::from(&source[..separator]),
+                base: parse_hex(&source[separator + 1..])?,
+            }),
+
+            None => Ok(SymbolSource {
+                path: PathBuf::from(source),
+                base: 0,
+            }),
+        }
+    }
+}
+
+struct Symbol {
+    address: u64,
+    // unknown for symbols of text maps without sizes
+    size: Option<u64>,
+    name: String,
+}
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+pub(crate) struct SymbolMap {
+    // sorted by address
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolMap {
+    pub fn from_sources(sources: &[SymbolSource]) -> Result<Self> {
+        let mut symbols = vec![];
+        for source in sources {
+            let is_elf = {
+                let mut magic = [0u8; 4];
+                File::open(&source.path)?.read_exact(&mut magic).is_ok() && magic[..] == *ELF_MAGIC
+            };
+
+            if is_elf {
+                SymbolMap::read_elf(source, &mut symbols)?;
+            } else {
+                SymbolMap::read_text_map(source, &mut symbols)?;
+            }
+        }
+
+        symbols.sort_by_key(|symbol| symbol.address);
+
+        Ok(SymbolMap { symbols })
+    }
+
+    fn read_elf(source: &SymbolSource, symbols: &mut Vec<Symbol>) -> Result<()> {
+        let data = fs::read(&source.path)?;
+        let elf = Elf::parse(&data).map_err(|err| {
+            application_error!(format!("bad ELF file {}: {}", source.path.display(), err))
+        })?;
+
+        let symbol_tables = [(&elf.syms, &elf.strtab), (&elf.dynsyms, &elf.dynstrtab)];
+        for (symbol_table, string_table) in symbol_tables.iter() {
+            for symbol in symbol_table.iter() {
+                if symbol.st_value == 0
+                    || !(symbol.is_function() || symbol.st_type() == sym::STT_OBJECT)
+                {
+                    continue;
+                }
+
+                if let Some(Ok(name)) = string_table.get(symbol.st_name) {
+                    symbols.push(Symbol {
+                        address: source.base.wrapping_add(symbol.st_value),
+                        size: Some(symbol.st_size),
+                        name: name.to_owned(),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // lines of nm (address [size] type name), System.map or /proc/kallsyms
+    // (address type name [module]); undefined symbols have no address and are skipped
+    fn read_text_map(source: &SymbolSource, symbols: &mut Vec<Symbol>) -> Result<()> {
+        for line in BufReader::new(File::open(&source.path)?).lines() {
+            let line = line?;
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if fields.len() < 3 {
+                continue;
+            }
+
+            let address = match parse_hex(fields[0]) {
+                Ok(0) | Err(_) => continue,
+
+                Ok(address) => address,
+            };
+
+            let (size, name) = if fields.len() >= 4 && fields[2].len() == 1 {
+                match parse_hex(fields[1]) {
+                    Ok(size) => (Some(size), fields[3]),
+
+                    Err(_) => (None, fields[2]),
+                }
+            } else {
+                (None, fields[2])
+            };
+
+            symbols.push(Symbol {
+                address: source.base.wrapping_add(address),
+                size,
+                name: name.to_owned(),
+            });
+        }
+
+        Ok(())
+    }
+
+    // nearest symbol at or below the address, and the offset from it
+    pub fn lookup(&self, address: u64) -> Option<(&str, u64)> {
+        let candidate = match self
+            .symbols
+            .binary_search_by_key(&address, |symbol| symbol.address)
+        {
+            Ok(index) => index,
+
+            Err(0) => return None,
+
+            Err(index) => index - 1,
+        };
+
+        let symbol = &self.symbols[candidate];
+        let offset = address - symbol.address;
+        match symbol.size {
+            Some(size) if size > 0 && offset >= size => None,
+
+            _ => Some((&symbol.name, offset)),
+        }
+    }
+
+    // func+0x1c
+    pub fn symbolize(&self, address: u64) -> Option<String> {
+        self.lookup(address).map(|(name, offset)| {
+            if offset == 0 {
+                name.to_owned()
+            } else {
+                format!("{}+0x{:x}", name, offset)
+            }
+        })
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use lz4::Decoder;
+use scroll::IOread;
+
+use crate::error::Result;
+
+pub(crate) enum TraceBasicBlockData {
+    // basic block is not translated yet, its data follows
+    Untranslated(Vec<u8>),
+
+    // basic block has been translated, its data is at this index of the raw basic block list
+    Translated(u64),
+}
+
+pub(crate) struct TraceRecord {
+    pub data: TraceBasicBlockData,
+    pub program_counter: u64,
+    pub execution_mode: u8,
+    pub execution_privilege: u8,
+    pub loop_count: u64,
+}
+
+pub(crate) struct TraceReader {
+    stream: BufReader<Decoder<File>>,
+}
+
+impl TraceReader {
+    pub fn from_file(trace_file: &Path) -> Result<Self> {
+        let stream = BufReader::new(Decoder::new(File::open(trace_file)?)?);
+        Ok(TraceReader { stream })
+    }
+
+    fn read_record(&mut self) -> Result<Option<TraceRecord>> {
+        let mut is_translated = [0u8; 1];
+        if self.stream.read(&mut is_translated)? == 0 {
+            return Ok(None);
+        }
+
+        let data = match is_translated[0] {
+            0 => {
+                let raw_size = self.stream.ioread::<u16>()?;
+                let mut raw_data = vec![0u8; raw_size as usize];
+                self.stream.read_exact(&mut raw_data)?;
+                TraceBasicBlockData::Untranslated(raw_data)
+            }
+
+            1 => TraceBasicBlockData::Translated(self.stream.ioread::<u64>()?),
+
+            _ => return Err(application_error!("bad translated flag in trace record")),
+        };
+
+        let program_counter = self.stream.ioread::<u64>()?;
+        let execution_mode = self.stream.ioread::<u8>()?;
+        let execution_privilege = self.stream.ioread::<u8>()?;
+        let loop_count = self.stream.ioread::<u64>()?;
+
+        Ok(Some(TraceRecord {
+            data,
+            program_counter,
+            execution_mode,
+            execution_privilege,
+            loop_count,
+        }))
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+pub(crate) fn total_basic_block_count(metadata_file: &Path) -> Result<u64> {
+    let mut metadata = File::open(metadata_file)?;
+    metadata.ioread::<u64>().map_err(From::from)
+}
@@ -0,0 +1,306 @@
+use std::{collections::HashMap, io::Write};
+
+use strum::AsStaticRef;
+use strum_macros::AsStaticStr;
+use tabwriter::TabWriter;
+use zydis::{BranchType, Mnemonic};
+
+use crate::{
+    args::{ExecutionMode, ExecutionPrivilege},
+    cache::BasicBlock,
+    disasm::{DisasmBasicBlock, DisasmInst},
+    error::Result,
+    module::ModuleMap,
+    symbol::SymbolMap,
+};
+
+#[derive(AsStaticStr, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub(crate) enum Direction {
+    #[strum(serialize = "user -> kernel")]
+    Entry,
+
+    #[strum(serialize = "kernel -> user")]
+    Exit,
+}
+
+impl Direction {
+    pub fn between(from: ExecutionPrivilege, to: ExecutionPrivilege) -> Option<Self> {
+        match (from, to) {
+            (ExecutionPrivilege::User, ExecutionPrivilege::Kernel) => Some(Direction::Entry),
+
+            (ExecutionPrivilege::Kernel, ExecutionPrivilege::User) => Some(Direction::Exit),
+
+            _ => None,
+        }
+    }
+}
+
+#[derive(AsStaticStr, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub(crate) enum Cause {
+    #[strum(serialize = "syscall")]
+    Syscall,
+
+    #[strum(serialize = "sysenter")]
+    Sysenter,
+
+    #[strum(serialize = "int")]
+    Interrupt,
+
+    // the last user instruction does not change privilege by itself: a fault, a trap or an
+    // external interrupt
+    #[strum(serialize = "exception")]
+    Exception,
+
+    #[strum(serialize = "iret")]
+    Iret,
+
+    #[strum(serialize = "sysret")]
+    Sysret,
+
+    #[strum(serialize = "sysexit")]
+    Sysexit,
+
+    #[strum(serialize = "other")]
+    Other,
+}
+
+impl Cause {
+    // from the last basic block executed before the privilege change
+    pub fn from_basic_block(disasm_basic_block: &DisasmBasicBlock, direction: Direction) -> Self {
+        let mnemonic = disasm_basic_block
+            .last_instruction()
+            .map(|ins| ins.instruction.mnemonic);
+
+        match direction {
+            Direction::Entry => match mnemonic {
+                Some(Mnemonic::SYSCALL) => Cause::Syscall,
+
+                Some(Mnemonic::SYSENTER) => Cause::Sysenter,
+
+                Some(Mnemonic::INT) | Some(Mnemonic::INT1) | Some(Mnemonic::INT3)
+                | Some(Mnemonic::INTO) => Cause::Interrupt,
+
+                _ => Cause::Exception,
+            },
+
+            Direction::Exit => match mnemonic {
+                Some(Mnemonic::IRET) | Some(Mnemonic::IRETD) | Some(Mnemonic::IRETQ) => Cause::Iret,
+
+                Some(Mnemonic::SYSRET) => Cause::Sysret,
+
+                Some(Mnemonic::SYSEXIT) => Cause::Sysexit,
+
+                _ => Cause::Other,
+            },
+        }
+    }
+}
+
+pub(crate) struct Transition {
+    pub direction: Direction,
+    pub cause: Cause,
+    // last basic block before the privilege change
+    pub from_index: usize,
+    pub from_program_counter: u64,
+    // first basic block after the privilege change
+    pub to_index: usize,
+    pub to_program_counter: u64,
+}
+
+impl Transition {
+    pub fn new(
+        (from_index, from_basic_block): (usize, &BasicBlock),
+        (to_index, to_basic_block): (usize, &BasicBlock),
+        direction: Direction,
+        cause: Cause,
+    ) -> Self {
+        Transition {
+            direction,
+            cause,
+            from_index,
+            from_program_counter: from_basic_block.program_counter,
+            to_index,
+            to_program_counter: to_basic_block.program_counter,
+        }
+    }
+}
+
+// transitions in trace order, and their counts per entry point (i.e. the first basic block
+// after the privilege change)
+pub(crate) struct TransitionCollector {
+    transitions: Vec<Transition>,
+    entry_points: HashMap<(Direction, u64, Cause), u64>,
+}
+
+impl TransitionCollector {
+    pub fn new() -> Self {
+        TransitionCollector {
+            transitions: vec![],
+            entry_points: HashMap::new(),
+        }
+    }
+
+    pub fn add_transition(&mut self, transition: Transition) {
+        *self
+            .entry_points
+            .entry((
+                transition.direction,
+                transition.to_program_counter,
+                transition.cause,
+            ))
+            .or_insert(0) += 1;
+        self.transitions.push(transition);
+    }
+
+    pub fn write_table<W: Write>(
+        &self,
+        output: &mut W,
+        summary_only: bool,
+        symbols: &SymbolMap,
+        module_map: &ModuleMap,
+    ) -> Result<()> {
+        let mut tw = TabWriter::new(output).padding(4);
+
+        if !summary_only {
+            writeln!(tw, "direction\tcause\tfrom\tfrom pc\tto\tto pc")?;
+            for transition in &self.transitions {
+                writeln!(
+                    tw,
+                    "{}\t{}\t{}\t0x{:016x}\t{}\t0x{:016x}",
+                    transition.direction.as_static(),
+                    transition.cause.as_static(),
+                    transition.from_index,
+                    transition.from_program_counter,
+                    transition.to_index,
+                    transition.to_program_counter
+                )?;
+            }
+            writeln!(tw)?;
+        }
+
+        let mut entry_points: Vec<_> = self.entry_points.iter().collect();
+        entry_points
+            .sort_by(|(key0, count0), (key1, count1)| count1.cmp(count0).then(key0.cmp(key1)));
+
+        writeln!(tw, "direction\tentry point\tmodule\tsymbol\tcause\tcount")?;
+        for ((direction, entry_point, cause), count) in entry_points {
+            writeln!(
+                tw,
+                "{}\t0x{:016x}\t{}\t{}\t{}\t{}",
+                direction.as_static(),
+                entry_point,
+                module_map
+                    .tag(*entry_point)
+                    .unwrap_or_else(|| "-".to_owned()),
+                symbols
+                    .symbolize(*entry_point)
+                    .unwrap_or_else(|| "-".to_owned()),
+                cause.as_static(),
+                count
+            )?;
+        }
+        tw.flush()?;
+
+        Ok(())
+    }
+}
+
+// a switch between the compatibility and the 64-bit submodes of long mode (e.g. WoW64
+// "heaven's gate"), i.e. a change of code segment at the same privilege
+pub(crate) struct ModeSwitch {
+    pub execution_privilege: ExecutionPrivilege,
+    pub from_mode: ExecutionMode,
+    pub to_mode: ExecutionMode,
+    pub from_index: usize,
+    pub from_program_counter: u64,
+    pub to_index: usize,
+    pub to_program_counter: u64,
+    // address and disassembly of the far transfer which caused the switch, if any
+    pub far_transfer: Option<(u64, String)>,
+}
+
+impl ModeSwitch {
+    pub fn is_switch(from_basic_block: &BasicBlock, to_basic_block: &BasicBlock) -> bool {
+        let long_submodes = (
+            from_basic_block.execution_mode,
+            to_basic_block.execution_mode,
+        );
+        from_basic_block.execution_privilege == to_basic_block.execution_privilege
+            && (long_submodes == (ExecutionMode::Compat, ExecutionMode::Bit64)
+                || long_submodes == (ExecutionMode::Bit64, ExecutionMode::Compat))
+    }
+
+    pub fn new(
+        (from_index, from_basic_block): (usize, &BasicBlock),
+        (to_index, to_basic_block): (usize, &BasicBlock),
+        disasm_basic_block: &DisasmBasicBlock,
+    ) -> Self {
+        ModeSwitch {
+            execution_privilege: from_basic_block.execution_privilege,
+            from_mode: from_basic_block.execution_mode,
+            to_mode: to_basic_block.execution_mode,
+            from_index,
+            from_program_counter: from_basic_block.program_counter,
+            to_index,
+            to_program_counter: to_basic_block.program_counter,
+            far_transfer: disasm_basic_block
+                .last_instruction()
+                .filter(|ins| is_far_transfer(ins))
+                .map(|ins| (ins.address, ins.disasm.clone())),
+        }
+    }
+}
+
+// far jmp/call/ret, or iret which may also reload the code segment
+fn is_far_transfer(ins: &DisasmInst) -> bool {
+    match ins.instruction.mnemonic {
+        Mnemonic::IRET | Mnemonic::IRETD | Mnemonic::IRETQ => true,
+
+        _ => ins.instruction.meta.branch_type == BranchType::FAR,
+    }
+}
+
+pub(crate) fn write_mode_switches<W: Write>(
+    mode_switches: &[ModeSwitch],
+    output: &mut W,
+    symbols: &SymbolMap,
+    module_map: &ModuleMap,
+) -> Result<()> {
+    let mut tw = TabWriter::new(output).padding(4);
+
+    let symbol = |address| symbols.symbolize(address).unwrap_or_else(|| "-".to_owned());
+    let module = |address| module_map.tag(address).unwrap_or_else(|| "-".to_owned());
+
+    writeln!(
+        tw,
+        "privilege\tswitch\tfrom\tfrom pc\tfrom module\tfrom symbol\tto\tto pc\tto module\t\
+         to symbol\tfar transfer"
+    )?;
+    for mode_switch in mode_switches {
+        let far_transfer = match mode_switch.far_transfer {
+            Some((address, ref disasm)) => format!("0x{:016x}  {}", address, disasm),
+
+            None => "-".to_owned(),
+        };
+
+        writeln!(
+            tw,
+            "{}\t{} -> {}\t{}\t0x{:016x}\t{}\t{}\t{}\t0x{:016x}\t{}\t{}\t{}",
+            mode_switch.execution_privilege.as_static(),
+            mode_switch.from_mode.as_static(),
+            mode_switch.to_mode.as_static(),
+            mode_switch.from_index,
+            mode_switch.from_program_counter,
+            module(mode_switch.from_program_counter),
+            symbol(mode_switch.from_program_counter),
+            mode_switch.to_index,
+            mode_switch.to_program_counter,
+            module(mode_switch.to_program_counter),
+            symbol(mode_switch.to_program_counter),
+            far_transfer
+        )?;
+    }
+    tw.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,574 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    io::Write,
+    path::Path,
+};
+
+use fasthash::metro;
+use goblin::{
+    elf::{
+        header::EM_X86_64,
+        program_header::PT_LOAD,
+        reloc::{R_386_RELATIVE, R_X86_64_RELATIVE},
+        Elf,
+    },
+    pe::PE,
+    Object,
+};
+use tabwriter::TabWriter;
+
+use crate::{cache::BasicBlock, error::Result, module::ModuleMap};
+
+const PAGE_MASK: u64 = !0xfff;
+// trace and file bytes shown for a mismatching basic block
+const SHOWN_BYTES: usize = 16;
+
+// ref: https://docs.microsoft.com/en-us/windows/desktop/debug/pe-format#base-relocation-types
+const IMAGE_REL_BASED_ABSOLUTE: u16 = 0;
+const IMAGE_REL_BASED_HIGH: u16 = 1;
+const IMAGE_REL_BASED_LOW: u16 = 2;
+const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+
+// file-backed range of a loaded image: offsets from the module base mapped to file offsets
+struct ImageRegion {
+    module_offset: u64,
+    file_offset: u64,
+    size: u64,
+}
+
+// content of a module file, relocated for the base where the module is loaded; ELF and PE
+// files are mapped by their segments or sections, any other file is taken as a flat image
+// of the module
+struct ModuleImage {
+    data: Vec<u8>,
+    regions: Vec<ImageRegion>,
+    // file offsets of the relocated bytes whose loaded value is not known (e.g. relocations
+    // against symbols), they are not compared
+    masked_offsets: HashSet<usize>,
+}
+
+impl ModuleImage {
+    fn from_file(path: &Path, base: u64) -> Result<Self> {
+        let data = fs::read(path)?;
+
+        let mut image = ModuleImage {
+            data: vec![],
+            regions: vec![],
+            masked_offsets: HashSet::new(),
+        };
+
+        match Object::parse(&data) {
+            Ok(Object::Elf(elf)) => {
+                let image_base = image.map_elf(&elf);
+                image.data = data.clone();
+                image.relocate_elf(&elf, image_base, base.wrapping_sub(image_base));
+            }
+
+            Ok(Object::PE(pe)) => {
+                image.map_pe(&pe);
+                image.data = data.clone();
+                image.relocate_pe(&pe, base.wrapping_sub(pe.image_base as u64));
+            }
+
+            _ => {
+                image.regions.push(ImageRegion {
+                    module_offset: 0,
+                    file_offset: 0,
+                    size: data.len() as u64,
+                });
+                image.data = data;
+            }
+        }
+
+        Ok(image)
+    }
+
+    // the module base is where the lowest segment is loaded, returns its address in the file
+    fn map_elf(&mut self, elf: &Elf) -> u64 {
+        let loads: Vec<_> = elf
+            .program_headers
+            .iter()
+            .filter(|header| header.p_type == PT_LOAD)
+            .collect();
+        let image_base = loads
+            .iter()
+            .map(|header| header.p_vaddr & PAGE_MASK)
+            .min()
+            .unwrap_or(0);
+
+        self.regions = loads
+            .iter()
+            .map(|header| ImageRegion {
+                module_offset: header.p_vaddr - image_base,
+                file_offset: header.p_offset,
+                size: header.p_filesz,
+            })
+            .collect();
+
+        image_base
+    }
+
+    // relative relocations are applied with the load bias, the other ones (text relocations
+    // against symbols) are masked
+    fn relocate_elf(&mut self, elf: &Elf, image_base: u64, load_bias: u64) {
+        let (relative_type, width) = if elf.header.e_machine == EM_X86_64 {
+            (R_X86_64_RELATIVE, 8)
+        } else {
+            (R_386_RELATIVE, 4)
+        };
+
+        let relocations = elf
+            .dynrelas
+            .iter()
+            .chain(elf.dynrels.iter())
+            .chain(elf.pltrelocs.iter());
+        for relocation in relocations {
+            let module_offset = (relocation.r_offset as u64).wrapping_sub(image_base);
+            let file_offset = match self.file_offset(module_offset, width) {
+                Some(file_offset) => file_offset,
+
+                None => continue,
+            };
+
+            if relocation.r_type != relative_type {
+                self.masked_offsets.extend(file_offset..file_offset + width);
+                continue;
+            }
+
+            // the addend of a rel relocation is in place
+            let addend = if relocation.is_rela {
+                relocation.r_addend as u64
+            } else {
+                self.read(file_offset, width).unwrap_or(0)
+            };
+            self.write(file_offset, width, load_bias.wrapping_add(addend));
+        }
+    }
+
+    fn map_pe(&mut self, pe: &PE) {
+        self.regions = pe
+            .sections
+            .iter()
+            .map(|section| {
+                // the virtual size is often zero in packed binaries
+                let size = if section.virtual_size == 0 {
+                    section.size_of_raw_data
+                } else {
+                    section.size_of_raw_data.min(section.virtual_size)
+                };
+
+                ImageRegion {
+                    module_offset: u64::from(section.virtual_address),
+                    file_offset: u64::from(section.pointer_to_raw_data),
+                    size: u64::from(size),
+                }
+            })
+            .collect();
+    }
+
+    // base relocations are applied with the difference between the load base and the
+    // preferred one
+    fn relocate_pe(&mut self, pe: &PE, delta: u64) {
+        let relocation_table = match pe.header.optional_header {
+            Some(ref optional_header) => {
+                match *optional_header.data_directories.get_base_relocation_table() {
+                    Some(relocation_table) => relocation_table,
+
+                    None => return,
+                }
+            }
+
+            None => return,
+        };
+
+        let table_offset = match self.file_offset(
+            u64::from(relocation_table.virtual_address),
+            relocation_table.size as usize,
+        ) {
+            Some(table_offset) => table_offset,
+
+            None => return,
+        };
+        let table_end = table_offset + relocation_table.size as usize;
+
+        // blocks of a page address, the block size, then 16-bit entries of a type and an
+        // offset in the page
+        let mut block_offset = table_offset;
+        while block_offset + 8 <= table_end {
+            let page = match self.read(block_offset, 4) {
+                Some(page) => page,
+
+                None => break,
+            };
+            let block_size = match self.read(block_offset + 4, 4) {
+                Some(block_size) if block_size >= 8 => block_size as usize,
+
+                _ => break,
+            };
+
+            let entry_end = (block_offset + block_size).min(table_end);
+            for entry_offset in (block_offset + 8..entry_end).step_by(2) {
+                let entry = match self.read(entry_offset, 2) {
+                    Some(entry) => entry as u16,
+
+                    None => break,
+                };
+                self.apply_pe_relocation(entry >> 12, page + u64::from(entry & 0xfff), delta);
+            }
+
+            block_offset += block_size;
+        }
+    }
+
+    fn apply_pe_relocation(&mut self, relocation_type: u16, module_offset: u64, delta: u64) {
+        let width = match relocation_type {
+            IMAGE_REL_BASED_ABSOLUTE => return,
+
+            IMAGE_REL_BASED_HIGH | IMAGE_REL_BASED_LOW => 2,
+
+            IMAGE_REL_BASED_HIGHLOW => 4,
+
+            IMAGE_REL_BASED_DIR64 => 8,
+
+            _ => 4,
+        };
+
+        let file_offset = match self.file_offset(module_offset, width) {
+            Some(file_offset) => file_offset,
+
+            None => return,
+        };
+
+        match relocation_type {
+            IMAGE_REL_BASED_HIGHLOW | IMAGE_REL_BASED_DIR64 => {
+                if let Some(value) = self.read(file_offset, width) {
+                    self.write(file_offset, width, value.wrapping_add(delta));
+                }
+            }
+
+            // partial relocations depend on the neighbouring entries
+            _ => self.masked_offsets.extend(file_offset..file_offset + width),
+        }
+    }
+
+    // little-endian value of `width` bytes at the file offset
+    fn read(&self, file_offset: usize, width: usize) -> Option<u64> {
+        let bytes = self
+            .data
+            .get(file_offset..file_offset.checked_add(width)?)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0u64, |value, &byte| (value << 8) | u64::from(byte)),
+        )
+    }
+
+    // the value is truncated to `width` bytes
+    fn write(&mut self, file_offset: usize, width: usize, value: u64) {
+        if let Some(bytes) = self.data.get_mut(file_offset..file_offset + width) {
+            for (index, byte) in bytes.iter_mut().enumerate() {
+                *byte = (value >> (8 * index)) as u8;
+            }
+        }
+    }
+
+    // file offset of the range at the module offset, none if the range is not file-backed
+    fn file_offset(&self, module_offset: u64, size: usize) -> Option<usize> {
+        let end_offset = module_offset.checked_add(size as u64)?;
+        let region = self.regions.iter().find(|region| {
+            region.module_offset <= module_offset
+                && end_offset <= region.module_offset.saturating_add(region.size)
+        })?;
+
+        Some((region.file_offset + module_offset - region.module_offset) as usize)
+    }
+
+    // file bytes of the range at the module offset, and whether each one is compared
+    fn bytes(&self, module_offset: u64, size: usize) -> Option<(&[u8], Vec<bool>)> {
+        let begin = self.file_offset(module_offset, size)?;
+        let bytes = self.data.get(begin..begin + size)?;
+        let compared = (begin..begin + size)
+            .map(|file_offset| !self.masked_offsets.contains(&file_offset))
+            .collect();
+
+        Some((bytes, compared))
+    }
+}
+
+enum Verification {
+    Match,
+    // bytes of the file, and the number of them differing from the trace
+    Mismatch(Vec<u8>, usize),
+    // outside of any module, or of a module without file
+    Unmapped,
+    // in a module whose file cannot be read
+    UnreadableModule,
+    // in a module, but not in a file-backed part of it (e.g. bss, heap)
+    NotFileBacked,
+}
+
+struct Mismatch {
+    first_index: usize,
+    program_counter: u64,
+    module_tag: String,
+    size: usize,
+    differing_bytes: usize,
+    occurrences: u64,
+    trace_data: Vec<u8>,
+    file_data: Vec<u8>,
+}
+
+// outcomes of the distinct basic blocks of a module
+#[derive(Default)]
+struct ModuleSummary {
+    matched: usize,
+    mismatched: usize,
+    not_file_backed: usize,
+    unreadable: usize,
+}
+
+// basic blocks of the trace compared with the bytes of the module files at the same place;
+// a mismatch comes from code modified once loaded: self-modifying code, unpacking or hooks
+pub(crate) struct Verifier<'a> {
+    module_map: &'a ModuleMap,
+    // module files are read once, when first needed
+    images: HashMap<usize, Option<ModuleImage>>,
+    // distinct basic blocks, by program counter and hash of their data, already compared
+    verified_basic_blocks: HashSet<(u64, u64)>,
+    mismatches: HashMap<(u64, u64), Mismatch>,
+    matched: usize,
+    unmapped: usize,
+    unreadable_module: usize,
+    not_file_backed: usize,
+    module_summaries: BTreeMap<String, ModuleSummary>,
+}
+
+impl<'a> Verifier<'a> {
+    pub fn new(module_map: &'a ModuleMap) -> Result<Self> {
+        if !module_map
+            .modules()
+            .iter()
+            .any(|module| module.path.is_some())
+        {
+            return Err(application_error!(
+                "verifying needs a module map with paths to the module files"
+            ));
+        }
+
+        Ok(Verifier {
+            module_map,
+            images: HashMap::new(),
+            verified_basic_blocks: HashSet::new(),
+            mismatches: HashMap::new(),
+            matched: 0,
+            unmapped: 0,
+            unreadable_module: 0,
+            not_file_backed: 0,
+            module_summaries: BTreeMap::new(),
+        })
+    }
+
+    fn verify(&mut self, basic_block: &BasicBlock) -> Verification {
+        let (module_id, module) = match self.module_map.find(basic_block.program_counter) {
+            Some(found) => found,
+
+            None => return Verification::Unmapped,
+        };
+
+        let path = match module.path {
+            Some(ref path) => path,
+
+            None => return Verification::Unmapped,
+        };
+
+        if !self.images.contains_key(&module_id) {
+            let image = match ModuleImage::from_file(path, module.base) {
+                Ok(image) => Some(image),
+
+                Err(err) => {
+                    eprintln!("cannot read module file {}: {}", path.display(), err);
+                    None
+                }
+            };
+            self.images.insert(module_id, image);
+        }
+
+        let image = match self.images[&module_id] {
+            Some(ref image) => image,
+
+            None => return Verification::UnreadableModule,
+        };
+
+        let module_offset = basic_block.program_counter - module.base;
+        match image.bytes(module_offset, basic_block.data.len()) {
+            Some((file_data, compared)) => {
+                let differing_bytes = file_data
+                    .iter()
+                    .zip(&basic_block.data)
+                    .zip(compared)
+                    .filter(|((file_byte, trace_byte), compared)| {
+                        *compared && file_byte != trace_byte
+                    })
+                    .count();
+
+                if differing_bytes == 0 {
+                    Verification::Match
+                } else {
+                    Verification::Mismatch(file_data.to_vec(), differing_bytes)
+                }
+            }
+
+            None => Verification::NotFileBacked,
+        }
+    }
+
+    pub fn add_basic_block(&mut self, basic_block_index: usize, basic_block: &BasicBlock) {
+        let key = (
+            basic_block.program_counter,
+            metro::hash64(&basic_block.data),
+        );
+
+        if let Some(mismatch) = self.mismatches.get_mut(&key) {
+            mismatch.occurrences += 1;
+            return;
+        }
+
+        if self.verified_basic_blocks.contains(&key) {
+            return;
+        }
+
+        let verification = self.verify(basic_block);
+
+        if let Some((_, module)) = self.module_map.find(basic_block.program_counter) {
+            let module_summary = self
+                .module_summaries
+                .entry(module.name.clone())
+                .or_insert_with(ModuleSummary::default);
+            match verification {
+                Verification::Match => module_summary.matched += 1,
+
+                Verification::Mismatch(..) => module_summary.mismatched += 1,
+
+                Verification::UnreadableModule => module_summary.unreadable += 1,
+
+                Verification::NotFileBacked => module_summary.not_file_backed += 1,
+
+                Verification::Unmapped => (),
+            }
+        }
+
+        match verification {
+            Verification::Match => {
+                self.matched += 1;
+                self.verified_basic_blocks.insert(key);
+            }
+
+            Verification::Unmapped => {
+                self.unmapped += 1;
+                self.verified_basic_blocks.insert(key);
+            }
+
+            Verification::UnreadableModule => {
+                self.unreadable_module += 1;
+                self.verified_basic_blocks.insert(key);
+            }
+
+            Verification::NotFileBacked => {
+                self.not_file_backed += 1;
+                self.verified_basic_blocks.insert(key);
+            }
+
+            Verification::Mismatch(file_data, differing_bytes) => {
+                let mismatch = Mismatch {
+                    first_index: basic_block_index,
+                    program_counter: basic_block.program_counter,
+                    module_tag: self
+                        .module_map
+                        .tag(basic_block.program_counter)
+                        .unwrap_or_default(),
+                    size: basic_block.data.len(),
+                    differing_bytes,
+                    occurrences: 1,
+                    trace_data: basic_block.data.clone(),
+                    file_data,
+                };
+                self.mismatches.insert(key, mismatch);
+            }
+        }
+    }
+
+    // mismatching basic blocks in the order of the trace, then the counts of each outcome per
+    // module and in total
+    pub fn write_mismatches<W: Write>(&self, output: &mut W) -> Result<()> {
+        let mut mismatches: Vec<_> = self.mismatches.values().collect();
+        mismatches.sort_by_key(|mismatch| mismatch.first_index);
+
+        let hex = |data: &[u8]| {
+            let bytes: Vec<_> = data
+                .iter()
+                .take(SHOWN_BYTES)
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            if data.len() > SHOWN_BYTES {
+                format!("{} ..", bytes.join(" "))
+            } else {
+                bytes.join(" ")
+            }
+        };
+
+        let mut tw = TabWriter::new(output).padding(4);
+
+        writeln!(
+            tw,
+            "first index\tprogram counter\tmodule\tsize\tdiffering\toccurrences\ttrace\tfile"
+        )?;
+        for mismatch in &mismatches {
+            writeln!(
+                tw,
+                "{}\t0x{:016x}\t{}\t{}\t{}\t{}\t{}\t{}",
+                mismatch.first_index,
+                mismatch.program_counter,
+                mismatch.module_tag,
+                mismatch.size,
+                mismatch.differing_bytes,
+                mismatch.occurrences,
+                hex(&mismatch.trace_data),
+                hex(&mismatch.file_data)
+            )?;
+        }
+        writeln!(tw)?;
+
+        writeln!(
+            tw,
+            "module\tmatching\tmismatching\tnot file-backed\tunreadable module"
+        )?;
+        for (module_name, module_summary) in &self.module_summaries {
+            writeln!(
+                tw,
+                "{}\t{}\t{}\t{}\t{}",
+                module_name,
+                module_summary.matched,
+                module_summary.mismatched,
+                module_summary.not_file_backed,
+                module_summary.unreadable
+            )?;
+        }
+        writeln!(tw)?;
+
+        writeln!(tw, "matching basic blocks\t{}", self.matched)?;
+        writeln!(tw, "mismatching basic blocks\t{}", mismatches.len())?;
+        writeln!(tw, "not file-backed basic blocks\t{}", self.not_file_backed)?;
+        writeln!(
+            tw,
+            "unreadable module basic blocks\t{}",
+            self.unreadable_module
+        )?;
+        writeln!(tw, "unmapped basic blocks\t{}", self.unmapped)?;
+        tw.flush()?;
+
+        Ok(())
+    }
+}
@@ -8,7 +8,10 @@ open System
 
 type BasicBlock =
     { ProgramCounter : uint64
+      // 0: compatibility submode, 1: 64-bit mode (2: 32-bit legacy, 3: 16-bit protected
+      // and 4: real mode are reserved, see ExecutionMode in BBClient/src/cache.rs)
       ExecutionMode : uint8
+      // current privilege level, 0 to 3
       Privilege : uint8
       Data : byte []
       LoopCount : uint64 }